@@ -0,0 +1,15 @@
+-- Record where each track came from, so that only tracks from eligible pools are
+-- picked as answers. Pools are ordered from most to least pickable.
+
+CREATE TYPE track_pool AS ENUM ('chart', 'guess');
+
+ALTER TABLE track ADD COLUMN pool track_pool NOT NULL DEFAULT 'chart';
+
+-- Tracks which were guessed but never picked were most likely only inserted as guesses.
+-- Any which are in a chart will be moved back to the chart pool on the next refresh.
+UPDATE track SET pool = 'guess'
+WHERE id IN (SELECT track_id FROM game_guess)
+    AND id NOT IN (SELECT track_id FROM game)
+    AND id NOT IN (SELECT track_id FROM daily_track);
+
+ALTER TABLE track ALTER COLUMN pool DROP DEFAULT;
@@ -0,0 +1,60 @@
+-- Widen music IDs to 64 bits.
+--
+-- IDs were previously converted from unsigned 32 bit integers by wrapping, so any
+-- which were stored as negative numbers are unwrapped. Foreign keys are dropped while
+-- this happens, and then recreated.
+
+ALTER TABLE album_genre
+    DROP CONSTRAINT album_genre_album_id_fkey,
+    DROP CONSTRAINT album_genre_genre_id_fkey;
+ALTER TABLE track
+    DROP CONSTRAINT track_artist_id_fkey,
+    DROP CONSTRAINT track_album_id_fkey;
+ALTER TABLE game
+    DROP CONSTRAINT game_genre_id_fkey,
+    DROP CONSTRAINT game_track_id_fkey;
+ALTER TABLE game_guess DROP CONSTRAINT game_guess_track_id_fkey;
+ALTER TABLE daily_track DROP CONSTRAINT daily_track_track_id_fkey;
+
+ALTER TABLE genre ALTER COLUMN id TYPE BIGINT;
+ALTER TABLE album ALTER COLUMN id TYPE BIGINT;
+ALTER TABLE artist ALTER COLUMN id TYPE BIGINT;
+ALTER TABLE album_genre
+    ALTER COLUMN album_id TYPE BIGINT,
+    ALTER COLUMN genre_id TYPE BIGINT;
+ALTER TABLE track
+    ALTER COLUMN id TYPE BIGINT,
+    ALTER COLUMN artist_id TYPE BIGINT,
+    ALTER COLUMN album_id TYPE BIGINT;
+ALTER TABLE game
+    ALTER COLUMN genre_id TYPE BIGINT,
+    ALTER COLUMN track_id TYPE BIGINT;
+ALTER TABLE game_guess ALTER COLUMN track_id TYPE BIGINT;
+ALTER TABLE daily_track ALTER COLUMN track_id TYPE BIGINT;
+
+UPDATE genre SET id = id + 4294967296 WHERE id < 0;
+UPDATE album SET id = id + 4294967296 WHERE id < 0;
+UPDATE artist SET id = id + 4294967296 WHERE id < 0;
+UPDATE album_genre SET album_id = album_id + 4294967296 WHERE album_id < 0;
+UPDATE album_genre SET genre_id = genre_id + 4294967296 WHERE genre_id < 0;
+UPDATE track SET id = id + 4294967296 WHERE id < 0;
+UPDATE track SET artist_id = artist_id + 4294967296 WHERE artist_id < 0;
+UPDATE track SET album_id = album_id + 4294967296 WHERE album_id < 0;
+UPDATE game SET genre_id = genre_id + 4294967296 WHERE genre_id < 0;
+UPDATE game SET track_id = track_id + 4294967296 WHERE track_id < 0;
+UPDATE game_guess SET track_id = track_id + 4294967296 WHERE track_id < 0;
+UPDATE daily_track SET track_id = track_id + 4294967296 WHERE track_id < 0;
+
+ALTER TABLE album_genre
+    ADD CONSTRAINT album_genre_album_id_fkey FOREIGN KEY (album_id) REFERENCES album(id),
+    ADD CONSTRAINT album_genre_genre_id_fkey FOREIGN KEY (genre_id) REFERENCES genre(id);
+ALTER TABLE track
+    ADD CONSTRAINT track_artist_id_fkey FOREIGN KEY (artist_id) REFERENCES artist(id),
+    ADD CONSTRAINT track_album_id_fkey FOREIGN KEY (album_id) REFERENCES album(id);
+ALTER TABLE game
+    ADD CONSTRAINT game_genre_id_fkey FOREIGN KEY (genre_id) REFERENCES genre(id),
+    ADD CONSTRAINT game_track_id_fkey FOREIGN KEY (track_id) REFERENCES track(id);
+ALTER TABLE game_guess
+    ADD CONSTRAINT game_guess_track_id_fkey FOREIGN KEY (track_id) REFERENCES track(id);
+ALTER TABLE daily_track
+    ADD CONSTRAINT daily_track_track_id_fkey FOREIGN KEY (track_id) REFERENCES track(id);
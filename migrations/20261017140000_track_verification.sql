@@ -0,0 +1,8 @@
+-- Track when each track was last confirmed to still exist with the music provider, so
+-- that stale tracks can be re-checked in the background.
+ALTER TABLE track ADD COLUMN verified_at TIMESTAMP WITH TIME ZONE NOT NULL
+    DEFAULT TIMEZONE('utc', NOW());
+
+-- When the provider stopped serving a track which is still referenced by games, and so
+-- can't be deleted. Such tracks are never picked.
+ALTER TABLE track ADD COLUMN gone_at TIMESTAMP WITH TIME ZONE DEFAULT NULL;
@@ -0,0 +1,39 @@
+-- Support picking tracks weighted by rank without sorting the whole track table.
+--
+-- Tracks are grouped into buckets by the order of magnitude of their rank, and each
+-- track gets a random sort key, so a track can be sampled from a bucket with an
+-- index seek. The total rank of each bucket is precomputed in `pick_weight`.
+ALTER TABLE track
+    ADD COLUMN pick_key DOUBLE PRECISION NOT NULL DEFAULT random(),
+    ADD COLUMN pick_bucket SMALLINT NOT NULL
+        GENERATED ALWAYS AS (FLOOR(LOG(2, GREATEST(deezer_rank, 1)))::SMALLINT) STORED;
+
+CREATE INDEX track_pick_bucket_idx ON track (pick_bucket, pick_key)
+    WHERE pool <> 'guess' AND gone_at IS NULL;
+CREATE INDEX track_pick_key_idx ON track (pick_key)
+    WHERE pool <> 'guess' AND gone_at IS NULL;
+
+-- Checking whether a user has played a track, and finding their least recent games.
+CREATE INDEX game_account_track_idx ON game (account_id, track_id, started_at);
+
+-- Filtering tracks by genre.
+CREATE INDEX album_genre_genre_idx ON album_genre (genre_id, album_id);
+
+-- The total rank of pickable tracks in each bucket, overall (genre 0) and per genre.
+CREATE TABLE pick_weight (
+    genre_id BIGINT NOT NULL,
+    bucket SMALLINT NOT NULL,
+    weight BIGINT NOT NULL,
+    PRIMARY KEY (genre_id, bucket)
+);
+
+INSERT INTO pick_weight (genre_id, bucket, weight)
+SELECT 0, pick_bucket, SUM(deezer_rank) FROM track
+WHERE pool <> 'guess' AND gone_at IS NULL
+GROUP BY pick_bucket;
+
+INSERT INTO pick_weight (genre_id, bucket, weight)
+SELECT album_genre.genre_id, track.pick_bucket, SUM(track.deezer_rank) FROM track
+INNER JOIN album_genre ON track.album_id = album_genre.album_id
+WHERE track.pool <> 'guess' AND track.gone_at IS NULL
+GROUP BY album_genre.genre_id, track.pick_bucket;
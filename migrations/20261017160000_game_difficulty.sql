@@ -0,0 +1,21 @@
+-- How strongly a game's track was picked towards popular tracks.
+CREATE TYPE game_difficulty AS ENUM ('mainstream', 'balanced', 'deep_cuts');
+
+ALTER TABLE game ADD COLUMN difficulty game_difficulty NOT NULL DEFAULT 'balanced';
+
+-- The number of pickable tracks in each bucket, so that picks can be weighted by
+-- something other than total rank.
+ALTER TABLE pick_weight ADD COLUMN track_count BIGINT NOT NULL DEFAULT 0;
+
+UPDATE pick_weight SET track_count = counts.track_count
+FROM (
+    SELECT 0 AS genre_id, pick_bucket, COUNT(*) AS track_count FROM track
+    WHERE pool <> 'guess' AND gone_at IS NULL
+    GROUP BY pick_bucket
+    UNION ALL
+    SELECT album_genre.genre_id, track.pick_bucket, COUNT(*) FROM track
+    INNER JOIN album_genre ON track.album_id = album_genre.album_id
+    WHERE track.pool <> 'guess' AND track.gone_at IS NULL
+    GROUP BY album_genre.genre_id, track.pick_bucket
+) AS counts
+WHERE pick_weight.genre_id = counts.genre_id AND pick_weight.bucket = counts.pick_bucket;
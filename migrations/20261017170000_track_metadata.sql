@@ -0,0 +1,11 @@
+-- Richer track and album metadata. Rows inserted before this are filled in as they
+-- are refreshed, so every column is nullable.
+ALTER TABLE track
+    ADD COLUMN duration INTEGER,
+    ADD COLUMN explicit_lyrics BOOLEAN,
+    ADD COLUMN bpm REAL,
+    ADD COLUMN isrc TEXT;
+
+ALTER TABLE album
+    ADD COLUMN release_date DATE,
+    ADD COLUMN record_type TEXT;
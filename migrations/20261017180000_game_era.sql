@@ -0,0 +1,11 @@
+-- Era games only pick tracks released within a range of years (inclusive).
+ALTER TABLE game
+    ADD COLUMN era_start_year INTEGER,
+    ADD COLUMN era_end_year INTEGER,
+    ADD CONSTRAINT game_era_check CHECK (
+        (era_start_year IS NULL) = (era_end_year IS NULL)
+        AND era_start_year <= era_end_year
+    );
+
+-- Filtering tracks by release date.
+CREATE INDEX album_release_date_idx ON album (release_date);
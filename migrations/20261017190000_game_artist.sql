@@ -0,0 +1,5 @@
+-- Artist games only pick from the top tracks of an artist, and optionally of artists
+-- related to them.
+ALTER TABLE game
+    ADD COLUMN artist_id BIGINT REFERENCES artist(id),
+    ADD COLUMN related_artists BOOLEAN NOT NULL DEFAULT false;
@@ -0,0 +1,14 @@
+-- Information on a playlist which a game was started from.
+CREATE TABLE playlist (
+    -- The playlist ID (from Deezer)
+    id BIGINT PRIMARY KEY,
+    -- The playlist title
+    title TEXT NOT NULL,
+    -- A link to the playlist on Deezer
+    deezer_url TEXT NOT NULL,
+    -- A link to the playlist's picture
+    picture_url TEXT NOT NULL
+);
+
+-- Playlist games only pick from the tracks in a playlist.
+ALTER TABLE game ADD COLUMN playlist_id BIGINT REFERENCES playlist(id);
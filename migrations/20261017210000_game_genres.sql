@@ -0,0 +1,16 @@
+-- The genres a genre-specific game picks its track from, replacing `game.genre_id`
+-- so that a game can draw from several genres at once.
+CREATE TABLE game_genre (
+    -- The game
+    game_id INTEGER REFERENCES game(id) ON DELETE CASCADE NOT NULL,
+
+    -- One of the genres the game's track was picked from
+    genre_id BIGINT REFERENCES genre(id) NOT NULL,
+
+    PRIMARY KEY (game_id, genre_id)
+);
+
+INSERT INTO game_genre (game_id, genre_id)
+SELECT id, genre_id FROM game WHERE genre_id IS NOT NULL;
+
+ALTER TABLE game DROP COLUMN genre_id;
@@ -0,0 +1,3 @@
+-- Whether tracks with explicit lyrics may be picked for the user's games, or null to
+-- use the instance-wide default.
+ALTER TABLE account ADD COLUMN allow_explicit BOOLEAN DEFAULT NULL;
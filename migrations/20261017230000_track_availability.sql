@@ -0,0 +1,5 @@
+-- Whether the track can be streamed at all from where the server is.
+ALTER TABLE track ADD COLUMN readable BOOLEAN NOT NULL DEFAULT true;
+
+-- Codes of the countries the track can be streamed in, or null if unknown.
+ALTER TABLE track ADD COLUMN available_countries TEXT[];
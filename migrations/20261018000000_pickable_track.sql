@@ -0,0 +1,51 @@
+-- Define which tracks can be picked for a game in one place, so that every picking
+-- query (and the precomputed pick weights) applies the same filters.
+--
+-- These are simple SQL functions, so Postgres inlines them into the calling query
+-- and can still use the partial indexes on `track`.
+
+-- Tracks which can be played at all: still served by the provider, readable, available
+-- in the configured region (if any) and only explicit if explicit tracks are allowed.
+CREATE FUNCTION playable_track(allow_explicit BOOLEAN, region TEXT)
+RETURNS SETOF track
+LANGUAGE SQL STABLE
+AS $$
+    SELECT * FROM track
+    WHERE track.gone_at IS NULL
+        AND track.readable
+        AND (allow_explicit OR track.explicit_lyrics IS NOT TRUE)
+        AND (region IS NULL OR track.available_countries IS NULL
+            OR region = ANY(track.available_countries))
+$$;
+
+-- Playable tracks which can be picked as the answer to a genre, era or random game:
+-- not only known from guesses, verified within the last 30 days, in any of the given
+-- genres (or any genre, if empty) and released in the given window (if any).
+CREATE FUNCTION pickable_track(
+    genre_ids BIGINT[],
+    released_from DATE,
+    released_until DATE,
+    allow_explicit BOOLEAN,
+    region TEXT
+)
+RETURNS SETOF track
+LANGUAGE SQL STABLE
+AS $$
+    SELECT * FROM playable_track(allow_explicit, region) AS track
+    WHERE track.pool <> 'guess'
+        AND track.verified_at > TIMEZONE('utc', NOW()) - MAKE_INTERVAL(days => 30)
+        AND (CARDINALITY(genre_ids) = 0 OR track.album_id IN (
+            SELECT album_id FROM album_genre WHERE genre_id = ANY(genre_ids)
+        ))
+        AND (released_from IS NULL OR track.album_id IN (
+            SELECT id FROM album WHERE release_date BETWEEN released_from AND released_until
+        ))
+$$;
+
+-- Pick weights are now precomputed separately for when explicit tracks are excluded.
+-- Existing weights included explicit tracks; they are recalculated on the next refresh.
+ALTER TABLE pick_weight
+    ADD COLUMN allow_explicit BOOLEAN NOT NULL DEFAULT true,
+    DROP CONSTRAINT pick_weight_pkey,
+    ADD PRIMARY KEY (genre_id, allow_explicit, bucket);
+ALTER TABLE pick_weight ALTER COLUMN allow_explicit DROP DEFAULT;
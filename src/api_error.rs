@@ -1,12 +1,14 @@
 //! Handle errors that may occur while handling a request.
-use std::io::Cursor;
+use std::{io::Cursor, time::Duration};
 
 use rocket::{
-    http::{ContentType, Status},
+    http::{ContentType, Header, Status},
     response::{self, Responder},
     Request, Response,
 };
 
+use crate::provider::Unavailable;
+
 /// Any error that may occur while handling a request.
 #[derive(Debug)]
 pub enum ApiError {
@@ -16,19 +18,39 @@ pub enum ApiError {
     ///
     /// The status code should be in the 4xx range.
     Client((Status, &'static str)),
+    /// The client has made too many requests, and should try again after the given time.
+    TooManyRequests(Duration),
+    /// The music provider is temporarily unavailable, so the client should try again
+    /// after the given time.
+    Unavailable(Duration),
 }
 
 #[rocket::async_trait]
 impl<'r> Responder<'r, 'static> for ApiError {
     fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = Response::build();
         let (status, text) = match self {
             Self::Internal(e) => {
                 eprintln!("{e:?}");
                 (Status::InternalServerError, "internal server error")
             }
             Self::Client((status, text)) => (status, text),
+            Self::TooManyRequests(retry_after) => {
+                response.header(retry_after_header(retry_after));
+                (
+                    Status::TooManyRequests,
+                    "too many requests, try again later",
+                )
+            }
+            Self::Unavailable(retry_after) => {
+                response.header(retry_after_header(retry_after));
+                (
+                    Status::ServiceUnavailable,
+                    "music provider unavailable, try again later",
+                )
+            }
         };
-        Response::build()
+        response
             .header(ContentType::Plain)
             .status(status)
             .sized_body(text.len(), Cursor::new(text))
@@ -36,9 +58,21 @@ impl<'r> Responder<'r, 'static> for ApiError {
     }
 }
 
+/// Build a `Retry-After` header for a delay.
+fn retry_after_header(retry_after: Duration) -> Header<'static> {
+    // Round up, so that clients don't retry before the time is up.
+    let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    Header::new("Retry-After", secs.to_string())
+}
+
 impl<T: Into<eyre::Report>> From<T> for ApiError {
     fn from(e: T) -> Self {
-        Self::Internal(e.into())
+        let e = e.into();
+        let retry_after = e
+            .root_cause()
+            .downcast_ref::<Unavailable>()
+            .map(|unavailable| unavailable.retry_after);
+        retry_after.map_or_else(|| Self::Internal(e), Self::Unavailable)
     }
 }
 
@@ -75,7 +109,38 @@ impl ApiError {
         let status = match self {
             Self::Internal(_) => Status::InternalServerError,
             Self::Client((status, _)) => status,
+            Self::TooManyRequests(_) => Status::TooManyRequests,
+            Self::Unavailable(_) => Status::ServiceUnavailable,
         };
         rocket::request::Outcome::Error((status, self))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::{get, local::asynchronous::Client, routes};
+
+    #[get("/unavailable")]
+    fn unavailable() -> Result<(), ApiError> {
+        Err(eyre::Report::new(Unavailable {
+            retry_after: Duration::from_millis(2500),
+        })
+        .wrap_err("error fetching chart")
+        .into())
+    }
+
+    #[rocket::async_test]
+    async fn unavailable_provider_is_503() {
+        let client = Client::untracked(rocket::build().mount("/", routes![unavailable]))
+            .await
+            .expect("rocket should be valid");
+        let response = client.get("/unavailable").dispatch().await;
+        assert_eq!(response.status(), Status::ServiceUnavailable);
+        assert_eq!(
+            response.headers().get_one("Retry-After"),
+            Some("3"),
+            "Retry-After should be rounded up"
+        );
+    }
+}
@@ -32,7 +32,7 @@ impl<'c> std::ops::Deref for Transaction<'c> {
     }
 }
 
-impl<'c> std::ops::DerefMut for Transaction<'c> {
+impl std::ops::DerefMut for Transaction<'_> {
     fn deref_mut(&mut self) -> &mut Self::Target {
         &mut self.0
     }
@@ -85,17 +85,17 @@ pub async fn run_migrations(rocket: rocket::Rocket<rocket::Build>) -> rocket::fa
     }
 }
 
-impl From<i32> for deezer::Id {
-    #[allow(clippy::cast_sign_loss)]
-    fn from(id: i32) -> Self {
-        Self(id as u32)
+impl From<i64> for deezer::Id {
+    /// IDs in the database are never negative, since they were all converted from IDs.
+    fn from(id: i64) -> Self {
+        Self(u64::try_from(id).expect("IDs in the database should never be negative"))
     }
 }
 
-impl From<deezer::Id> for i32 {
-    #[allow(clippy::cast_possible_wrap)]
+impl From<deezer::Id> for i64 {
+    /// IDs are checked to fit in an `i64` when they are deserialised.
     fn from(id: deezer::Id) -> Self {
-        id.0 as Self
+        Self::try_from(id.0).expect("IDs should always fit in an i64")
     }
 }
 
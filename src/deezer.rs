@@ -1,190 +1,735 @@
 //! A minimal wrapper for the parts of the Deezer API we care about.
 //! API documentation: <https://developers.deezer.com/api>
+//!
+//! The types here also serve as the data model for music data in general, whichever
+//! [`MusicProvider`] it comes from.
+use chrono::NaiveDate;
+use duration_string::DurationString;
 use eyre::{eyre, Context, Result};
-use lazy_static::lazy_static;
-use reqwest::RequestBuilder;
+use reqwest::{RequestBuilder, StatusCode};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
-// We don't actually use Rocket here, but Reqwest also uses this `Bytes` type and doesn't re-export it.
-use crate::ratelimit::{Backoff, Ratelimit};
-use futures::{Stream, StreamExt};
-use rocket::http::hyper::body::Bytes;
 
-/// Base URL for the API.
+use crate::{
+    provider::{MusicProvider, PreviewExpired, PreviewStream, Unavailable},
+    ratelimit::{Backoff, CircuitBreaker, Ratelimit},
+};
+use futures::StreamExt;
+use rocket::{http::hyper::body::Bytes, serde::json::serde_json, tokio::fs};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fmt,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+/// Default base URL for the API.
 const API_URL: &str = "https://api.deezer.com";
 
-lazy_static! {
-    /// A shared HTTP client for making requests to the API.
-    static ref CLIENT: reqwest::Client = {
-        let mut headers = reqwest::header::HeaderMap::new();
-        headers.insert(reqwest::header::ACCEPT_LANGUAGE, "en".parse().unwrap());
-        reqwest::Client::builder().default_headers(headers).build().unwrap()
-    };
+/// Config options for the Deezer client. All are optional.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Base URL for the API, to allow using a mirror or a mock server.
+    url: String,
+    /// The language to request localised data (such as genre names) in.
+    language: String,
+    /// How long to wait for a whole request before giving up (no limit by default).
+    timeout: Option<DurationString>,
+    /// How long to wait for a connection to be established before giving up.
+    connect_timeout: Option<DurationString>,
+    /// A proxy URL to send all requests through.
+    proxy: Option<String>,
+    /// The maximum number of API requests to allow at once (at least 1, since no
+    /// request could ever be made with 0).
+    ratelimit_capacity: usize,
+    /// How much of the rate limit capacity is reserved for interactive requests, so
+    /// that background work such as catalog refreshes can't starve users.
+    ratelimit_reserved: usize,
+    /// How long to wait before allowing another API request.
+    ratelimit_interval: DurationString,
+    /// Whether to record or replay API responses and previews, for testing.
+    mode: Mode,
+    /// The directory fixtures are recorded to or replayed from (required unless
+    /// `mode` is `live`).
+    fixtures_dir: Option<PathBuf>,
+    /// Whether to cache API responses on disk (only in live mode).
+    cache: bool,
+    /// How long to cache responses from each endpoint for, by the first part of the
+    /// path (such as `genre` or `search`), overriding [`DEFAULT_CACHE_TTLS`].
+    cache_ttl: HashMap<String, DurationString>,
+    /// The maximum number of times to try each API request.
+    max_attempts: u32,
+    /// How long to spend on each API request, including retries, before giving up.
+    request_deadline: DurationString,
+    /// How many consecutive failed attempts make us stop sending requests for a while.
+    breaker_threshold: u32,
+    /// How long to stop sending requests for after too many failures.
+    breaker_cooldown: DurationString,
+}
+
+/// How long to cache responses from each endpoint for by default, in seconds.
+///
+/// Endpoints not listed here are not cached.
+const DEFAULT_CACHE_TTLS: [(&str, u64); 7] = [
+    ("genre", 24 * 60 * 60),
+    ("artist", 24 * 60 * 60),
+    ("chart", 6 * 60 * 60),
+    ("album", 7 * 24 * 60 * 60),
+    ("playlist", 24 * 60 * 60),
+    ("search", 60 * 60),
+    // short, since track data such as preview URLs changes
+    ("track", 60 * 60),
+];
+
+/// Whether the Deezer client talks to the network, and whether it keeps a record.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Mode {
+    /// Make real requests and don't record them.
+    #[default]
+    Live,
+    /// Make real requests, and save every response as a fixture.
+    Record,
+    /// Make no requests, and serve responses from previously recorded fixtures.
+    Replay,
+}
+
+impl Default for Config {
+    /// Deezer currently allows 50 requests per 5 seconds, and the default rate
+    /// limit should align with that.
+    fn default() -> Self {
+        Self {
+            url: API_URL.into(),
+            language: "en".into(),
+            timeout: None,
+            connect_timeout: None,
+            proxy: None,
+            ratelimit_capacity: 50,
+            ratelimit_reserved: 10,
+            ratelimit_interval: Duration::from_millis(100).into(),
+            mode: Mode::Live,
+            fixtures_dir: None,
+            cache: true,
+            cache_ttl: HashMap::new(),
+            max_attempts: 5,
+            request_deadline: Duration::from_secs(30).into(),
+            breaker_threshold: 5,
+            breaker_cooldown: Duration::from_mins(1).into(),
+        }
+    }
+}
 
+/// A client for the Deezer API.
+pub struct Deezer {
+    /// The HTTP client used to make requests to the API.
+    client: reqwest::Client,
+    /// Base URL for the API, without a trailing slash.
+    url: String,
     /// A rate limiter for the Deezer API.
+    ratelimit: Ratelimit,
+    /// Where to record or replay responses, if not in live mode.
+    fixtures: Option<Fixtures>,
+    /// The response cache, if enabled.
+    cache: Option<Cache>,
+    /// The maximum number of times to try each API request.
+    max_attempts: u32,
+    /// How long to spend on each API request, including retries.
+    request_deadline: Duration,
+    /// Stops us sending requests while the API is failing.
+    breaker: CircuitBreaker,
+}
+
+impl Deezer {
+    /// Create a new client using the given config.
     ///
-    /// Deezer currently allows 50 requests per 5 seconds, and this configuration
-    /// should align with that.
-    static ref RATELIMIT: Ratelimit = Ratelimit::new(50, std::time::Duration::from_millis(100));
-}
-
-/// Make a request to the Deezer API, respecting the rate limit and retrying
-/// if we hit it or the service is busy, with exponential backoff.
-async fn send_request<T: DeserializeOwned + Send>(req: RequestBuilder) -> Result<Option<T>> {
-    let backoff = Backoff::new(
-        std::time::Duration::from_secs(1),
-        std::time::Duration::from_secs(300),
-        2,
-    );
-    for delay in backoff {
-        RATELIMIT.wait().await;
-        let response = req
+    /// The response cache, if enabled, is kept under `media_dir`.
+    pub fn new(config: &Config, media_dir: &Path) -> Result<Self> {
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert(
+            reqwest::header::ACCEPT_LANGUAGE,
+            config
+                .language
+                .parse()
+                .wrap_err("invalid Deezer language")?,
+        );
+        let mut client = reqwest::Client::builder().default_headers(headers);
+        if let Some(timeout) = config.timeout {
+            client = client.timeout(timeout.into());
+        }
+        if let Some(timeout) = config.connect_timeout {
+            client = client.connect_timeout(timeout.into());
+        }
+        if let Some(proxy) = &config.proxy {
+            client = client.proxy(reqwest::Proxy::all(proxy).wrap_err("invalid Deezer proxy")?);
+        }
+        let fixtures = match config.mode {
+            Mode::Live => None,
+            mode => {
+                let dir = config
+                    .fixtures_dir
+                    .clone()
+                    .ok_or_else(|| eyre!("`fixtures_dir` is required to record or replay"))?;
+                // fine to use blocking API here, only called on startup
+                std::fs::create_dir_all(&dir).wrap_err("error creating fixtures directory")?;
+                Some(Fixtures { mode, dir })
+            }
+        };
+        let cache = if config.cache && config.mode == Mode::Live {
+            let dir = media_dir.join("deezer_cache");
+            std::fs::create_dir_all(&dir).wrap_err("error creating Deezer cache directory")?;
+            let mut ttls: HashMap<_, _> = DEFAULT_CACHE_TTLS
+                .iter()
+                .map(|(endpoint, secs)| ((*endpoint).to_string(), Duration::from_secs(*secs)))
+                .collect();
+            ttls.extend(
+                config
+                    .cache_ttl
+                    .iter()
+                    .map(|(endpoint, ttl)| (endpoint.clone(), (*ttl).into())),
+            );
+            Some(Cache { dir, ttls })
+        } else {
+            None
+        };
+        Ok(Self {
+            client: client
+                .build()
+                .wrap_err("error building Deezer HTTP client")?,
+            url: config.url.trim_end_matches('/').into(),
+            ratelimit: Ratelimit::new(
+                config.ratelimit_capacity.max(1),
+                config.ratelimit_reserved,
+                config.ratelimit_interval.into(),
+            ),
+            fixtures,
+            cache,
+            max_attempts: config.max_attempts.max(1),
+            request_deadline: config.request_deadline.into(),
+            breaker: CircuitBreaker::new(
+                config.breaker_threshold.max(1),
+                config.breaker_cooldown.into(),
+            ),
+        })
+    }
+
+    /// Whether responses are replayed from fixtures rather than requested.
+    fn replaying(&self) -> bool {
+        self.fixtures
+            .as_ref()
+            .is_some_and(|f| f.mode == Mode::Replay)
+    }
+
+    /// Create a client which replays the fixtures recorded under `fixtures/deezer`.
+    #[cfg(test)]
+    pub fn replay() -> Self {
+        let config = Config {
+            mode: Mode::Replay,
+            fixtures_dir: Some(Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/deezer")),
+            ..Config::default()
+        };
+        Self::new(&config, &std::env::temp_dir()).expect("replay client should build")
+    }
+
+    /// Send a single request to the Deezer API and get the raw response body.
+    ///
+    /// This replays the response if fixtures are being replayed. Responses are
+    /// recorded by [`Self::try_fetch`], once they are known not to be errors. The
+    /// caller must wait for the rate limit first.
+    async fn send(&self, req: &reqwest::Request) -> Result<Bytes> {
+        let req = req
             .try_clone()
-            .expect("reqwest request cloning should not fail")
-            .send()
+            .expect("reqwest request cloning should not fail");
+        if let Some(fixtures) = self.fixtures.as_ref().filter(|f| f.mode == Mode::Replay) {
+            return fixtures.load(req.url(), "json").await;
+        }
+        let body = self
+            .client
+            .execute(req)
             .await
             .wrap_err("error sending request to Deezer")?
             .error_for_status()
             .wrap_err("Deezer API returned an HTTP error")?
-            .json()
+            .bytes()
             .await
-            .wrap_err("error deserialising Deezer API response")?;
-        match response {
-            Response::Data(data) => return Ok(Some(data)),
-            Response::Error { error } => match error.code {
-                ErrorCode::Ratelimited | ErrorCode::ServiceBusy => {
-                    eprintln!(
-                        "Deezer API returned a temporary error, retrying in {}s: {error}",
-                        delay.as_secs()
-                    );
-                    tokio::time::sleep(delay).await;
+            .wrap_err("error reading Deezer API response")?;
+        Ok(body)
+    }
+
+    /// Make a request to the Deezer API, respecting the rate limit and retrying
+    /// if it fails or the service is busy, with exponential backoff.
+    ///
+    /// Retries are bounded by both a number of attempts and a deadline, and no requests
+    /// are made while the circuit breaker is open. In either case, an [`Unavailable`]
+    /// error is returned. The deadline starts once the first request is allowed by the
+    /// rate limit, and running out of time while waiting for the rate limit is not
+    /// counted towards the circuit breaker, since Deezer itself may be fine.
+    ///
+    /// Successful responses are served from and saved to the cache, if enabled.
+    ///
+    /// Returns `None` if the resource was not found.
+    async fn try_fetch<T: DeserializeOwned + Send>(
+        &self,
+        req: RequestBuilder,
+    ) -> Result<Option<T>> {
+        let req = req.build().wrap_err("error building Deezer request")?;
+        if let Some(cache) = &self.cache {
+            if let Some(body) = cache.get(req.url()).await {
+                if let Ok(Response::Data(data)) = serde_json::from_slice(&body) {
+                    return Ok(Some(data));
                 }
-                ErrorCode::NotFound => return Ok(None),
-                ErrorCode::Unknown(_) => {
-                    return Err(eyre!("Deezer API returned an unknown error: {error}"))
+            }
+        }
+        let mut deadline = None;
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_mins(5), 2);
+        for attempt in 1..=self.max_attempts {
+            self.breaker
+                .check()
+                .map_err(|retry_after| Unavailable { retry_after })?;
+            if !self.replaying() {
+                self.ratelimit.wait().await;
+            }
+            let deadline = *deadline.get_or_insert_with(|| Instant::now() + self.request_deadline);
+            if Instant::now() >= deadline {
+                eprintln!("Deezer request ran out of time waiting for the rate limit");
+                break;
+            }
+            let Ok(result) = tokio::time::timeout_at(deadline.into(), self.send(&req)).await else {
+                self.breaker.failure();
+                eprintln!("Deezer request timed out after {attempt} attempts");
+                break;
+            };
+            let error = match result.and_then(|body| {
+                serde_json::from_slice(&body)
+                    .map(|response| (body, response))
+                    .wrap_err("error deserialising Deezer API response")
+            }) {
+                Ok((body, Response::Data(data))) => {
+                    self.breaker.success();
+                    if let Some(cache) = &self.cache {
+                        cache.put(req.url(), &body).await;
+                    }
+                    if let Some(fixtures) =
+                        self.fixtures.as_ref().filter(|f| f.mode == Mode::Record)
+                    {
+                        fixtures.save(req.url(), "json", &body).await?;
+                    }
+                    return Ok(Some(data));
                 }
-            },
+                Ok((_, Response::Error { error })) => match error.code {
+                    ErrorCode::Ratelimited | ErrorCode::ServiceBusy => {
+                        eyre!("Deezer API returned a temporary error: {error}")
+                    }
+                    ErrorCode::NotFound => {
+                        self.breaker.success();
+                        return Ok(None);
+                    }
+                    ErrorCode::Unknown(_) => {
+                        self.breaker.success();
+                        return Err(eyre!("Deezer API returned an unknown error: {error}"));
+                    }
+                },
+                // Retrying can't make a fixture appear, and the upstream service is
+                // fine, so don't count this towards the circuit breaker.
+                Err(e) if e.downcast_ref::<MissingFixture>().is_some() => return Err(e),
+                Err(e) => e,
+            };
+            if self.breaker.failure() {
+                eprintln!("too many failed Deezer requests, pausing requests: {error:?}");
+                break;
+            }
+            let delay = backoff.next().expect("backoff iterator should never end");
+            if attempt == self.max_attempts || Instant::now() + delay >= deadline {
+                eprintln!("giving up on Deezer request after {attempt} attempts: {error:?}");
+                break;
+            }
+            eprintln!(
+                "Deezer request failed, retrying in {}s: {error:?}",
+                delay.as_secs()
+            );
+            tokio::time::sleep(delay).await;
         }
+        let retry_after = self
+            .breaker
+            .check()
+            .err()
+            .unwrap_or_else(|| backoff.next().expect("backoff iterator should never end"));
+        Err(Unavailable { retry_after }.into())
+    }
+
+    /// Make a request to the Deezer API, returning an error if the resource was not found.
+    async fn fetch<T: DeserializeOwned + Send>(&self, req: RequestBuilder) -> Result<T> {
+        self.try_fetch(req)
+            .await
+            .and_then(|data| data.ok_or_else(|| eyre!("requested resource not found")))
     }
-    unreachable!("backoff iterator should never end")
 }
 
-/// An extension trait allowing for the use of a custom send method on
-/// [`RequestBuilder`] with postfix syntax.
-#[rocket::async_trait]
-trait RequestBuilderExt {
-    /// Send a request to the Deezer API, returning `None` if the resource was not found.
-    async fn try_deezer_fetch<T: DeserializeOwned + Send>(self) -> Result<Option<T>>;
+/// Recorded Deezer responses, saved as one file per URL.
+struct Fixtures {
+    /// Whether to record or replay (never [`Mode::Live`]).
+    mode: Mode,
+    /// The directory fixture files are kept in.
+    dir: PathBuf,
+}
+
+impl Fixtures {
+    /// Load a previously recorded response.
+    ///
+    /// Returns a [`MissingFixture`] error if there is no recording for the URL.
+    async fn load(&self, url: &reqwest::Url, ext: &str) -> Result<Bytes> {
+        let path = self.dir.join(file_name(url, ext));
+        let data = fs::read(&path).await.map_err(|e| {
+            eyre::Report::new(e).wrap_err(MissingFixture {
+                url: url.to_string(),
+                path: path.clone(),
+            })
+        })?;
+        Ok(data.into())
+    }
 
-    /// Send a request to the Deezer API, returning an error if the resource was not found.
-    async fn deezer_fetch<T: DeserializeOwned + Send>(self) -> Result<T>;
+    /// Record a response, replacing any previous recording for the same URL.
+    async fn save(&self, url: &reqwest::Url, ext: &str, data: &[u8]) -> Result<()> {
+        fs::write(self.dir.join(file_name(url, ext)), data)
+            .await
+            .wrap_err("error saving Deezer fixture")
+    }
 }
 
-#[rocket::async_trait]
-impl RequestBuilderExt for RequestBuilder {
-    async fn try_deezer_fetch<T: DeserializeOwned + Send>(self) -> Result<Option<T>> {
-        send_request(self).await
+/// The error returned in replay mode when no fixture was recorded for a request.
+#[derive(Debug)]
+struct MissingFixture {
+    /// The URL of the request.
+    url: String,
+    /// Where the fixture was expected to be.
+    path: PathBuf,
+}
+
+impl fmt::Display for MissingFixture {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "no Deezer fixture recorded for {} ({})",
+            self.url,
+            self.path.display()
+        )
+    }
+}
+
+/// A disk cache of successful API responses, saved as one file per URL.
+struct Cache {
+    /// The directory cached responses are kept in.
+    dir: PathBuf,
+    /// How long to keep responses from each endpoint for.
+    ttls: HashMap<String, Duration>,
+}
+
+impl Cache {
+    /// Get how long to cache responses for a URL, if at all.
+    fn ttl(&self, url: &reqwest::Url) -> Option<Duration> {
+        let endpoint = url.path_segments()?.next()?;
+        self.ttls
+            .get(endpoint)
+            .copied()
+            .filter(|ttl| !ttl.is_zero())
     }
 
-    async fn deezer_fetch<T: DeserializeOwned + Send>(self) -> Result<T> {
-        send_request(self)
+    /// Get a cached response, if there is one which hasn't expired. An expired
+    /// response is deleted.
+    ///
+    /// Errors reading the cache are treated as a miss.
+    async fn get(&self, url: &reqwest::Url) -> Option<Bytes> {
+        let ttl = self.ttl(url)?;
+        let path = self.dir.join(file_name(url, "json"));
+        let age = fs::metadata(&path)
             .await
-            .and_then(|data| data.ok_or_else(|| eyre!("requested resource not found")))
+            .ok()?
+            .modified()
+            .ok()?
+            .elapsed()
+            .ok()?;
+        if age > ttl {
+            let _ = fs::remove_file(&path).await;
+            return None;
+        }
+        fs::read(&path).await.ok().map(Into::into)
+    }
+
+    /// Delete every cached response older than the longest TTL, which must have
+    /// expired whichever endpoint it is from. Returns how many were deleted.
+    ///
+    /// Responses with shorter TTLs are deleted when next read after they expire, so
+    /// this only needs to catch responses which are never requested again.
+    async fn prune(&self) -> Result<usize> {
+        let max_ttl = self.ttls.values().max().copied().unwrap_or_default();
+        let mut entries = fs::read_dir(&self.dir)
+            .await
+            .wrap_err("error reading Deezer cache directory")?;
+        let mut removed = 0;
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .wrap_err("error reading Deezer cache directory")?
+        {
+            let expired = entry
+                .metadata()
+                .await
+                .ok()
+                .and_then(|meta| meta.modified().ok())
+                .and_then(|modified| modified.elapsed().ok())
+                .is_some_and(|age| age > max_ttl);
+            if expired && fs::remove_file(entry.path()).await.is_ok() {
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Remove any cached response for a URL.
+    ///
+    /// Errors are ignored, since a missing file is the usual case.
+    async fn remove(&self, url: &reqwest::Url) {
+        let _ = fs::remove_file(self.dir.join(file_name(url, "json"))).await;
+    }
+
+    /// Save a response to the cache, if its endpoint is cached.
+    ///
+    /// Errors are logged rather than returned, since the cache is only an optimisation.
+    async fn put(&self, url: &reqwest::Url, body: &[u8]) {
+        if self.ttl(url).is_none() {
+            return;
+        }
+        if let Err(e) = fs::write(self.dir.join(file_name(url, "json")), body).await {
+            eprintln!("error caching Deezer response: {e}");
+        }
     }
 }
 
-/// Fetch the "chart" (a list of popular tracks) for a given genre.
-/// The genre with ID 0 is "all genres".
-pub async fn chart(genre_id: Id) -> Result<Vec<Track>> {
-    let url = format!("{API_URL}/chart/{genre_id}/tracks");
-    let data: DataWrap<_> = CLIENT
-        .get(&url)
-        .deezer_fetch()
-        .await
-        .wrap_err("error fetching genre chart")?;
-    Ok(data.data)
+/// Get the name of the file to store a response from a URL in.
+///
+/// The host is ignored so that files saved using one base URL can be used with
+/// another. The name starts with a readable version of the path, followed by a
+/// hash of the path and query.
+fn file_name(url: &reqwest::Url, ext: &str) -> String {
+    let key = url.query().map_or_else(
+        || url.path().to_string(),
+        |query| format!("{}?{query}", url.path()),
+    );
+    let readable: String = url
+        .path()
+        .trim_matches('/')
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .take(64)
+        .collect();
+    let hash = Sha256::digest(key.as_bytes());
+    let hash = u64::from_be_bytes(hash[..8].try_into().expect("slice is 8 bytes"));
+    format!("{readable}-{hash:016x}.{ext}")
 }
 
 /// Genres we don't want to show.
-const GENRE_BLACKLIST: [u32; 2] = [
+const GENRE_BLACKLIST: [u64; 2] = [
     0,   // All
     457, // Audiobooks
 ];
 
-/// Get a list of common genres.
-/// There does not seem to be a way to get a list of all genres, short of enumerating
-/// all possible genre IDs.
-pub async fn genres() -> Result<Vec<Genre>> {
-    let url = format!("{API_URL}/genre");
-    let genres = CLIENT
-        .get(&url)
-        .deezer_fetch::<DataWrap<Vec<Genre>>>()
-        .await
-        .wrap_err("error fetching genre list")?
-        .data
-        .into_iter()
-        .filter(|genre| !GENRE_BLACKLIST.contains(&genre.id))
-        .collect();
-    Ok(genres)
+/// The maximum number of tracks to take from each playlist.
+const PLAYLIST_LIMIT: u32 = 100;
+
+/// The maximum number of top tracks to fetch for an artist.
+const ARTIST_TOP_LIMIT: u32 = 50;
+
+#[rocket::async_trait]
+impl MusicProvider for Deezer {
+    async fn chart(&self, genre_id: Id) -> Result<Vec<Track>> {
+        let url = format!("{}/chart/{genre_id}/tracks", self.url);
+        let data: DataWrap<_> = self
+            .fetch(self.client.get(&url))
+            .await
+            .wrap_err("error fetching genre chart")?;
+        Ok(data.data)
+    }
+
+    /// Deezer has no way to search by release date, so this uses the top search result
+    /// for a playlist of hits from each decade in the range, such as "1990s hits".
+    async fn era_chart(&self, start_year: i32, end_year: i32) -> Result<Vec<Track>> {
+        let url = format!("{}/search/playlist", self.url);
+        let mut tracks = Vec::new();
+        for decade in (start_year - start_year.rem_euclid(10)..=end_year).step_by(10) {
+            // Use the full year, since "10s hits" finds the 2010s rather than the 1910s.
+            let name = format!("{decade}s hits");
+            let results: DataWrap<Vec<Playlist>> = self
+                .fetch(self.client.get(&url).query(&[("q", &name)]))
+                .await
+                .wrap_err("error searching playlists")?;
+            if let Some(playlist) = results.data.first() {
+                tracks.extend(self.playlist_tracks(playlist.id).await?);
+            }
+        }
+        Ok(tracks)
+    }
+
+    async fn playlist(&self, playlist_id: Id) -> Result<Option<Playlist>> {
+        let url = format!("{}/playlist/{playlist_id}", self.url);
+        self.try_fetch(self.client.get(&url))
+            .await
+            .wrap_err("error fetching playlist")
+    }
+
+    /// Only the first [`PLAYLIST_LIMIT`] tracks are fetched.
+    async fn playlist_tracks(&self, playlist_id: Id) -> Result<Vec<Track>> {
+        let url = format!("{}/playlist/{playlist_id}/tracks", self.url);
+        let data: DataWrap<_> = self
+            .fetch(self.client.get(&url).query(&[("limit", PLAYLIST_LIMIT)]))
+            .await
+            .wrap_err("error fetching playlist tracks")?;
+        Ok(data.data)
+    }
+
+    async fn artist(&self, artist_id: Id) -> Result<Option<Artist>> {
+        let url = format!("{}/artist/{artist_id}", self.url);
+        self.try_fetch(self.client.get(&url))
+            .await
+            .wrap_err("error fetching artist")
+    }
+
+    async fn artist_top(&self, artist_id: Id) -> Result<Vec<Track>> {
+        let url = format!("{}/artist/{artist_id}/top", self.url);
+        let data: Option<DataWrap<_>> = self
+            .try_fetch(self.client.get(&url).query(&[("limit", ARTIST_TOP_LIMIT)]))
+            .await
+            .wrap_err("error fetching artist top tracks")?;
+        Ok(data.map(|data| data.data).unwrap_or_default())
+    }
+
+    async fn related_artists(&self, artist_id: Id) -> Result<Vec<Artist>> {
+        let url = format!("{}/artist/{artist_id}/related", self.url);
+        let data: Option<DataWrap<_>> = self
+            .try_fetch(self.client.get(&url))
+            .await
+            .wrap_err("error fetching related artists")?;
+        Ok(data.map(|data| data.data).unwrap_or_default())
+    }
+
+    /// There does not seem to be a way to get a list of all genres, short of enumerating
+    /// all possible genre IDs.
+    async fn genres(&self) -> Result<Vec<Genre>> {
+        let url = format!("{}/genre", self.url);
+        let genres = self
+            .fetch::<DataWrap<Vec<Genre>>>(self.client.get(&url))
+            .await
+            .wrap_err("error fetching genre list")?
+            .data
+            .into_iter()
+            .filter(|genre| !GENRE_BLACKLIST.contains(&genre.id))
+            .collect();
+        Ok(genres)
+    }
+
+    async fn album(&self, album_id: Id) -> Result<Album> {
+        let url = format!("{}/album/{album_id}", self.url);
+        self.fetch(self.client.get(&url))
+            .await
+            .wrap_err("error fetching album")
+    }
+
+    async fn track_search(&self, q: &str) -> Result<Vec<Track>> {
+        let url = format!("{}/search/track", self.url);
+        let data: DataWrap<_> = self
+            .fetch(self.client.get(&url).query(&[("q", q)]))
+            .await
+            .wrap_err("error searching tracks")?;
+        Ok(data.data)
+    }
+
+    async fn track(&self, id: Id) -> Result<Option<Track>> {
+        let url = format!("{}/track/{id}", self.url);
+        self.try_fetch(self.client.get(&url))
+            .await
+            .wrap_err("error fetching track")
+    }
+
+    async fn track_uncached(&self, id: Id) -> Result<Option<Track>> {
+        let url = format!("{}/track/{id}", self.url);
+        if let Some(cache) = &self.cache {
+            let url = reqwest::Url::parse(&url).wrap_err("invalid track URL")?;
+            cache.remove(&url).await;
+        }
+        self.try_fetch(self.client.get(&url))
+            .await
+            .wrap_err("error fetching track")
+    }
+
+    async fn prune_cache(&self) -> Result<()> {
+        if let Some(cache) = &self.cache {
+            let removed = cache.prune().await?;
+            eprintln!("pruned {removed} expired Deezer cache entries");
+        }
+        Ok(())
+    }
+
+    async fn track_preview(&self, preview_url: &str) -> Result<PreviewStream> {
+        if let Some(fixtures) = &self.fixtures {
+            let url = reqwest::Url::parse(preview_url).wrap_err("invalid track preview URL")?;
+            let data = if fixtures.mode == Mode::Replay {
+                fixtures.load(&url, "mp3").await?
+            } else {
+                let response = self
+                    .client
+                    .get(url.clone())
+                    .send()
+                    .await
+                    .wrap_err("error downloading a track preview")?;
+                let data = check_preview_status(response)?
+                    .bytes()
+                    .await
+                    .wrap_err("error downloading a track preview")?;
+                fixtures.save(&url, "mp3", &data).await?;
+                data
+            };
+            return Ok(futures::stream::once(async { Ok(data) }).boxed());
+        }
+        // This isn't an API request so hopefully should be fine without ratelimiting.
+        let response = self
+            .client
+            .get(preview_url)
+            .send()
+            .await
+            .wrap_err("error downloading a track preview")?;
+        Ok(check_preview_status(response)?
+            .bytes_stream()
+            .map(|chunk| chunk.wrap_err("error downloading track preview chunk"))
+            .boxed())
+    }
 }
 
-/// Get an album by its ID.
+/// Check the status of a track preview download.
 ///
-/// # Errors
-///
-/// Returns an error if the album does not exist, or if the API request fails
-/// for another reason.
-pub async fn album(album_id: Id) -> Result<Album> {
-    let url = format!("{API_URL}/album/{album_id}");
-    CLIENT
-        .get(&url)
-        .deezer_fetch()
-        .await
-        .wrap_err("error fetching album")
-}
-
-/// Search for a track by name or artist.
-pub async fn track_search(q: &str) -> Result<Vec<Track>> {
-    let url = format!("{API_URL}/search/track");
-    let data: DataWrap<_> = CLIENT
-        .get(&url)
-        .query(&[("q", q)])
-        .deezer_fetch()
-        .await
-        .wrap_err("error searching tracks")?;
-    Ok(data.data)
-}
-
-/// Fetch a track by ID, returning None if it was not found.
-pub async fn track(id: Id) -> Result<Option<Track>> {
-    let url = format!("{API_URL}/track/{id}");
-    CLIENT
-        .get(&url)
-        .try_deezer_fetch()
-        .await
-        .wrap_err("error fetching track")
-}
-
-/// Download a track from Deezer and save it to the music cache.
-pub async fn track_preview(preview_url: &str) -> Result<impl Stream<Item = Result<Bytes>>> {
-    // This isn't an API request so hopefully should be fine without ratelimiting.
-    let response = CLIENT
-        .get(preview_url)
-        .send()
-        .await
-        .wrap_err("error downloading a track preview")?;
-    Ok(response
-        .bytes_stream()
-        .map(|chunk| chunk.wrap_err("error downloading track preview chunk")))
+/// Preview URLs are signed and expire, after which Deezer responds with 403 Forbidden
+/// (or possibly 410 Gone), which is returned as a [`PreviewExpired`] error.
+fn check_preview_status(response: reqwest::Response) -> Result<reqwest::Response> {
+    match response.status() {
+        StatusCode::FORBIDDEN | StatusCode::GONE => Err(PreviewExpired.into()),
+        _ => response
+            .error_for_status()
+            .wrap_err("error downloading a track preview"),
+    }
 }
 
 /// A helper for serde deserialisation of API responses which are wrapped in
 /// an object with a single `data` field.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone)]
 pub struct DataWrap<T> {
     /// The actual data.
     data: T,
 }
 
+impl<T> From<T> for DataWrap<T> {
+    fn from(data: T) -> Self {
+        Self { data }
+    }
+}
+
 impl<T> std::ops::Deref for DataWrap<T> {
     type Target = T;
 
@@ -234,7 +779,7 @@ pub enum ErrorCode {
     /// The requested resource was not found.
     NotFound,
     /// Other error codes exist, but we treat them all as unresolvable issues.
-    Unknown(u32),
+    Unknown(#[allow(dead_code)] u32),
 }
 
 impl<'d> serde::Deserialize<'d> for ErrorCode {
@@ -254,7 +799,7 @@ impl<'d> serde::Deserialize<'d> for ErrorCode {
 }
 
 /// An artist object returned by the API.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Artist {
     /// Deezer ID
     pub id: Id,
@@ -266,8 +811,21 @@ pub struct Artist {
     pub picture: String,
 }
 
+/// A playlist object returned by the API, without its tracks.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Playlist {
+    /// Deezer ID
+    pub id: Id,
+    /// Playlist title
+    pub title: String,
+    /// Link to the playlist on Deezer
+    pub link: String,
+    /// URL of an image for the playlist
+    pub picture: String,
+}
+
 /// A genre object returned by the API.
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Genre {
     /// Deezer ID
     pub id: Id,
@@ -278,7 +836,7 @@ pub struct Genre {
 }
 
 /// A track object returned by the API.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone)]
 pub struct Track {
     /// Deezer ID
     pub id: Id,
@@ -294,12 +852,31 @@ pub struct Track {
     pub artist: Artist,
     /// Album object (missing some fields)
     pub album: PartialAlbum,
+    /// Track length in seconds, if known
+    #[serde(default)]
+    pub duration: Option<i32>,
+    /// Whether the track has explicit lyrics, if known
+    #[serde(default)]
+    pub explicit_lyrics: Option<bool>,
+    /// Beats per minute, only included in full track objects
+    #[serde(default, deserialize_with = "deserialize_bpm")]
+    pub bpm: Option<f32>,
+    /// International Standard Recording Code, only included in full track objects
+    #[serde(default)]
+    pub isrc: Option<String>,
+    /// Whether the track can be streamed from where the request was made
+    #[serde(default = "default_readable")]
+    pub readable: bool,
+    /// Codes of the countries the track can be streamed in, only included in full track
+    /// objects
+    #[serde(default)]
+    pub available_countries: Option<Vec<String>>,
 }
 
 /// A partial album object returned by the API as part of a track object.
 ///
-/// You can use the [`album`] function to get a full album object.
-#[derive(Debug, Deserialize)]
+/// You can use [`MusicProvider::album`] to get a full album object.
+#[derive(Debug, Deserialize, Clone)]
 pub struct PartialAlbum {
     /// Deezer ID
     pub id: Id,
@@ -310,7 +887,7 @@ pub struct PartialAlbum {
 }
 
 /// A full album object returned by the API.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone)]
 pub struct Album {
     /// Deezer ID
     pub id: Id,
@@ -322,18 +899,56 @@ pub struct Album {
     pub link: String,
     /// A list of genres associated with the album
     pub genres: DataWrap<Vec<Genre>>,
+    /// The album's release date, if known
+    #[serde(default, deserialize_with = "deserialize_date")]
+    pub release_date: Option<NaiveDate>,
+    /// The kind of release, such as `album`, `single`, `ep` or `compile`
+    #[serde(default)]
+    pub record_type: Option<String>,
+}
+
+/// Tracks are assumed to be readable unless the API says otherwise.
+const fn default_readable() -> bool {
+    true
+}
+
+/// Deserialise a BPM, which Deezer gives as `0` when it is unknown.
+fn deserialize_bpm<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<f32>, D::Error> {
+    let bpm = Option::<f32>::deserialize(deserializer)?;
+    Ok(bpm.filter(|bpm| *bpm > 0.0))
+}
+
+/// Deserialise a date, which Deezer gives as `0000-00-00` when it is unknown.
+fn deserialize_date<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<NaiveDate>, D::Error> {
+    let date = Option::<String>::deserialize(deserializer)?;
+    Ok(date.and_then(|date| NaiveDate::parse_from_str(&date, "%Y-%m-%d").ok()))
 }
 
 /// A wrapper around a Deezer ID.
 ///
 /// This is mainly useful to handle conversions to and from the database representation,
-/// which uses `i32` instead of `u32` (because SQL doesn't have unsigned integers).
-#[derive(Debug, Hash, PartialEq, Eq, Clone, Copy, Deserialize, Serialize)]
+/// which uses `i64` instead of `u64` (because SQL doesn't have unsigned integers). IDs
+/// above `i64::MAX` are rejected when deserialising, so conversions can't overflow.
+#[derive(Debug, Hash, PartialEq, Eq, Clone, Copy, Serialize)]
 #[serde(transparent)]
-pub struct Id(pub u32);
+pub struct Id(pub u64);
+
+impl<'de> Deserialize<'de> for Id {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let id = u64::deserialize(deserializer)?;
+        if i64::try_from(id).is_err() {
+            return Err(serde::de::Error::custom(format!("ID out of range: {id}")));
+        }
+        Ok(Self(id))
+    }
+}
 
 impl std::ops::Deref for Id {
-    type Target = u32;
+    type Target = u64;
 
     fn deref(&self) -> &Self::Target {
         &self.0
@@ -345,8 +960,8 @@ impl std::ops::Deref for Id {
 #[serde(transparent)]
 pub struct OptionId(Option<Id>);
 
-impl From<Option<i32>> for OptionId {
-    fn from(id: Option<i32>) -> Self {
+impl From<Option<i64>> for OptionId {
+    fn from(id: Option<i64>) -> Self {
         id.map_or(Self(None), |id| Self(Some(id.into())))
     }
 }
@@ -358,3 +973,183 @@ impl std::ops::Deref for OptionId {
         &self.0
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{api_error::ApiError, provider::MusicProvider};
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    /// A genre list response body.
+    const GENRES: &str = r#"{"data":[{"id":132,"name":"Pop","picture":"p"}]}"#;
+
+    /// A "service busy" error response body, which should be retried.
+    const BUSY: &str = r#"{"error":{"type":"Exception","message":"busy","code":700}}"#;
+
+    /// Serve the given response body to every request on a local port, returning the
+    /// base URL and a count of requests received.
+    fn serve(body: &'static str) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("should bind a local port");
+        let url = format!(
+            "http://{}",
+            listener.local_addr().expect("should have an address")
+        );
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().map_while(Result::ok) {
+                let mut reader = BufReader::new(&stream);
+                let mut line = String::new();
+                while reader.read_line(&mut line).is_ok_and(|n| n > 2) {
+                    line.clear();
+                }
+                counter.fetch_add(1, Ordering::SeqCst);
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+                    Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+            }
+        });
+        (url, requests)
+    }
+
+    /// Create an uncached client for a stand-in server.
+    fn live(url: String, config: Config) -> Deezer {
+        let config = Config {
+            url,
+            cache: false,
+            ..config
+        };
+        Deezer::new(&config, &std::env::temp_dir()).expect("live client should build")
+    }
+
+    #[rocket::async_test]
+    async fn replays_genres() {
+        let genres = Deezer::replay()
+            .genres()
+            .await
+            .expect("genres should replay");
+        let ids: Vec<_> = genres.iter().map(|genre| genre.id).collect();
+        assert_eq!(
+            ids,
+            [Id(132), Id(113)],
+            "blacklisted genres should be skipped"
+        );
+    }
+
+    #[rocket::async_test]
+    async fn replays_track_and_album() {
+        let deezer = Deezer::replay();
+        let track = deezer
+            .track(Id(3_135_556))
+            .await
+            .expect("track should replay")
+            .expect("track should exist");
+        assert_eq!(track.title, "Harder, Better, Faster, Stronger");
+        assert_eq!(track.duration, Some(224));
+        assert_eq!(track.explicit_lyrics, Some(false));
+        assert_eq!(track.bpm, Some(123.4));
+        let album = deezer
+            .album(track.album.id)
+            .await
+            .expect("album should replay");
+        assert_eq!(album.title, "Discovery");
+        assert_eq!(album.release_date, NaiveDate::from_ymd_opt(2001, 3, 7));
+        assert_eq!(album.genres.len(), 2);
+    }
+
+    #[rocket::async_test]
+    async fn missing_fixture_fails_without_retrying() {
+        let deezer = Deezer::replay();
+        let started = Instant::now();
+        for _ in 0..10 {
+            let error = deezer
+                .track(Id(1))
+                .await
+                .expect_err("track without a fixture should fail");
+            assert!(
+                error
+                    .chain()
+                    .any(|cause| cause.to_string().starts_with("no Deezer fixture")),
+                "unexpected error: {error:?}"
+            );
+        }
+        assert!(
+            started.elapsed() < Duration::from_secs(1),
+            "should not back off"
+        );
+        // The circuit breaker shouldn't have been tripped by the missing fixtures.
+        assert!(deezer.track(Id(3_135_556)).await.is_ok());
+    }
+
+    #[rocket::async_test]
+    async fn retries_are_capped() {
+        let (url, requests) = serve(BUSY);
+        let config = Config {
+            max_attempts: 2,
+            breaker_threshold: 10,
+            ..Config::default()
+        };
+        let error = live(url, config)
+            .genres()
+            .await
+            .expect_err("a busy service should fail");
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        assert!(Unavailable::caused(&error), "unexpected error: {error:?}");
+    }
+
+    #[rocket::async_test]
+    async fn circuit_breaker_stops_requests() {
+        let (url, requests) = serve(BUSY);
+        let config = Config {
+            breaker_threshold: 1,
+            ..Config::default()
+        };
+        let deezer = live(url, config);
+        for _ in 0..3 {
+            let error = deezer
+                .genres()
+                .await
+                .expect_err("a busy service should fail");
+            let ApiError::Unavailable(retry_after) = ApiError::from(error) else {
+                panic!("an open circuit should make the provider unavailable");
+            };
+            assert!(retry_after <= Duration::from_mins(1));
+        }
+        assert_eq!(
+            requests.load(Ordering::SeqCst),
+            1,
+            "no requests should be made while the circuit is open"
+        );
+    }
+
+    #[rocket::async_test]
+    async fn ratelimit_waits_are_not_failures() {
+        let (url, requests) = serve(GENRES);
+        let config = Config {
+            ratelimit_capacity: 1,
+            ratelimit_reserved: 0,
+            ratelimit_interval: Duration::from_millis(300).into(),
+            request_deadline: Duration::from_millis(100).into(),
+            breaker_threshold: 1,
+            ..Config::default()
+        };
+        let deezer = live(url, config);
+        for _ in 0..3 {
+            deezer
+                .genres()
+                .await
+                .expect("waiting for the rate limit shouldn't use up the deadline");
+        }
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+    }
+}
@@ -3,7 +3,11 @@ use chrono::{DateTime, Utc};
 use serde::Serialize;
 
 use super::logic::timed_game_cutoff;
-use crate::{deezer, track, DbConn, User};
+use crate::{
+    deezer, track,
+    track::pick::{Difficulty, Era},
+    DbConn, User,
+};
 use eyre::{Context, Result};
 
 /// A model directly representing a row in the `game` table.
@@ -19,14 +23,46 @@ pub struct Row {
     pub is_daily: bool,
     /// If this is a timed mode game. Mutually exclusive with `is_daily`.
     pub is_timed: bool,
-    /// If this is a genre-specific game, the genre ID. Otherwise `null`.
-    ///
-    /// Mutually exclusive with `is_daily`.
-    pub genre_id: deezer::OptionId,
     /// If the game has ended, whether the user won.
     pub won: Option<bool>,
     /// The ID of the track being guessed.
     pub track_id: deezer::Id,
+    /// How strongly popular tracks were preferred when picking the track.
+    pub difficulty: Difficulty,
+    /// If this is an era game, the first year of the era. Otherwise `null`.
+    pub era_start_year: Option<i32>,
+    /// If this is an era game, the last year of the era. Otherwise `null`.
+    pub era_end_year: Option<i32>,
+    /// If this is an artist game, the artist ID. Otherwise `null`.
+    ///
+    /// Mutually exclusive with `is_daily`, the genres and the era.
+    pub artist_id: deezer::OptionId,
+    /// If this is an artist game, whether tracks by related artists may also be picked.
+    pub related_artists: bool,
+    /// If this is a playlist game, the playlist ID. Otherwise `null`.
+    ///
+    /// Mutually exclusive with `is_daily`, the genres, the era and `artist_id`.
+    pub playlist_id: deezer::OptionId,
+}
+
+/// The options a game was started with, which decide how its track is picked.
+pub struct Mode {
+    /// If this is a daily mode game.
+    pub daily: bool,
+    /// If this is a timed mode game.
+    pub timed: bool,
+    /// If this is a genre-specific game, the genres to pick from. Otherwise empty.
+    pub genre_ids: Vec<deezer::Id>,
+    /// How strongly popular tracks are preferred when picking the track.
+    pub difficulty: Difficulty,
+    /// If this is an era game, the era.
+    pub era: Option<Era>,
+    /// If this is an artist game, the artist ID.
+    pub artist_id: Option<deezer::Id>,
+    /// If this is an artist game, whether to include related artists.
+    pub related_artists: bool,
+    /// If this is a playlist game, the playlist ID.
+    pub playlist_id: Option<deezer::Id>,
 }
 
 /// A single guess in a game.
@@ -45,6 +81,9 @@ pub struct Guess {
 pub struct Game {
     /// The game row.
     row: Row,
+    /// If this is a genre-specific game, the genres the track was picked from.
+    /// Otherwise empty.
+    pub genre_ids: Vec<deezer::Id>,
     /// The guesses made in this game.
     pub guesses: Vec<Guess>,
     /// Track metadata for the track being guessed. This is just a cache and
@@ -67,8 +106,26 @@ impl std::ops::DerefMut for Game {
 }
 
 impl Row {
-    /// Fetch the guesses associated with this game.
+    /// If this is an era game, the era.
+    pub fn era(&self) -> Option<Era> {
+        Some(Era {
+            start_year: self.era_start_year?,
+            end_year: self.era_end_year?,
+        })
+    }
+
+    /// Fetch the genres and guesses associated with this game.
     async fn with_guesses(self, db: &mut DbConn) -> Result<Game> {
+        let genre_ids = sqlx::query_scalar!(
+            "SELECT genre_id FROM game_genre WHERE game_id = $1 ORDER BY genre_id",
+            self.id,
+        )
+        .fetch_all(&mut *db)
+        .await
+        .wrap_err("error querying game genres")?
+        .into_iter()
+        .map(From::from)
+        .collect();
         let guesses = sqlx::query_as!(
             Guess,
             "SELECT track_id, guessed_at FROM game_guess
@@ -81,6 +138,7 @@ impl Row {
         .wrap_err("error querying game guesses")?;
         Ok(Game {
             row: self,
+            genre_ids,
             guesses,
             track_cache: None,
         })
@@ -94,26 +152,46 @@ impl Game {
     pub async fn create(
         db: &mut DbConn,
         user_id: i32,
-        genre_id: Option<deezer::Id>,
-        daily: bool,
-        timed: bool,
+        mode: &Mode,
         track_id: deezer::Id,
     ) -> Result<Self> {
         let game = sqlx::query_as!(
             Row,
-            "INSERT INTO game (account_id, is_daily, is_timed, genre_id, track_id)
-            VALUES ($1, $2, $3, $4, $5)
-            RETURNING *",
+            r#"INSERT INTO game (
+                account_id, is_daily, is_timed, difficulty,
+                era_start_year, era_end_year, artist_id, related_artists, playlist_id,
+                track_id
+            )
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+            RETURNING
+                id, account_id, started_at, is_daily, is_timed, won, track_id,
+                difficulty AS "difficulty: Difficulty", era_start_year, era_end_year,
+                artist_id, related_artists, playlist_id"#,
             user_id,
-            daily,
-            timed,
-            genre_id.map(i32::from),
-            i32::from(track_id),
+            mode.daily,
+            mode.timed,
+            mode.difficulty as Difficulty,
+            mode.era.map(|era| era.start_year),
+            mode.era.map(|era| era.end_year),
+            mode.artist_id.map(i64::from),
+            mode.related_artists,
+            mode.playlist_id.map(i64::from),
+            i64::from(track_id),
         )
-        .fetch_one(db)
+        .fetch_one(&mut *db)
         .await?;
+        let genre_ids: Vec<i64> = mode.genre_ids.iter().copied().map(i64::from).collect();
+        sqlx::query!(
+            "INSERT INTO game_genre (game_id, genre_id) SELECT $1, UNNEST($2::BIGINT[])",
+            game.id,
+            &genre_ids,
+        )
+        .execute(db)
+        .await
+        .wrap_err("error inserting game genres")?;
         Ok(Self {
             row: game,
+            genre_ids: mode.genre_ids.clone(),
             guesses: Vec::new(),
             track_cache: None,
         })
@@ -121,9 +199,17 @@ impl Game {
 
     /// Get a game from the database by ID.
     pub async fn get(db: &mut DbConn, id: i32) -> Result<Option<Self>> {
-        let Some(game) = sqlx::query_as!(Row, "SELECT * FROM game WHERE id = $1 FOR UPDATE", id)
-            .fetch_optional(&mut *db)
-            .await?
+        let Some(game) = sqlx::query_as!(
+            Row,
+            r#"SELECT
+                id, account_id, started_at, is_daily, is_timed, won, track_id,
+                difficulty AS "difficulty: Difficulty", era_start_year, era_end_year,
+                artist_id, related_artists, playlist_id
+            FROM game WHERE id = $1 FOR UPDATE"#,
+            id
+        )
+        .fetch_optional(&mut *db)
+        .await?
         else {
             return Ok(None);
         };
@@ -148,7 +234,7 @@ impl Game {
             VALUES ($1, $2, $3, $4)
             RETURNING track_id, guessed_at",
             self.id,
-            track_id.map(i32::from),
+            track_id.map(i64::from),
             i32::try_from(self.guesses.len()).expect("guess count to fit in i32"),
             guessed_at,
         )
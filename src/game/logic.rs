@@ -176,7 +176,7 @@ impl Game {
     }
 
     /// Whether the player has run out of guesses.
-    fn is_out_of_guesses(&self) -> bool {
+    const fn is_out_of_guesses(&self) -> bool {
         self.guesses.len() >= MAX_GUESSES
     }
 }
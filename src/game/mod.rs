@@ -4,6 +4,6 @@ mod logic;
 mod response;
 mod routes;
 
-pub use database::Game;
+pub use database::{Game, Mode};
 pub use response::Response;
 pub use routes::routes;
@@ -1,6 +1,10 @@
 //! The game response type, used for serialising games to JSON.
 use super::logic::{Constants, CurrentGuess, GenericConstants, CONSTANTS};
-use crate::{deezer, track, DbConn, Game};
+use crate::{
+    deezer, track,
+    track::pick::{Difficulty, Era},
+    DbConn, Game,
+};
 use chrono::{DateTime, Utc};
 use eyre::Result;
 use serde::Serialize;
@@ -9,8 +13,16 @@ impl Game {
     /// Convert a game into a response, leaving out information that the user
     /// should not be able to see.
     pub async fn into_response(self, db: &mut DbConn) -> Result<Response> {
-        let genre = match *self.genre_id {
-            Some(genre_id) => Some(track::genre(db, genre_id).await?),
+        let mut genres = Vec::with_capacity(self.genre_ids.len());
+        for genre_id in &self.genre_ids {
+            genres.push(track::genre(db, *genre_id).await?);
+        }
+        let artist = match *self.artist_id {
+            Some(artist_id) => Some(track::artist(db, artist_id).await?),
+            None => None,
+        };
+        let playlist = match *self.playlist_id {
+            Some(playlist_id) => Some(track::playlist(db, playlist_id).await?),
             None => None,
         };
         let timed_guess = if self.is_timed && self.won.is_none() {
@@ -22,6 +34,9 @@ impl Game {
         let started_at = self.started_at;
         let is_daily = self.is_daily;
         let is_timed = self.is_timed;
+        let difficulty = self.difficulty;
+        let era = self.era();
+        let related_artists = self.related_artists;
         let won = self.won;
         let track = match &self.won {
             Some(_) => Some(if let Some(track) = self.track_cache {
@@ -47,7 +62,12 @@ impl Game {
             started_at,
             is_daily,
             is_timed,
-            genre,
+            genres,
+            difficulty,
+            era,
+            artist,
+            related_artists,
+            playlist,
             won,
             guesses,
             timed_guess,
@@ -69,10 +89,28 @@ pub struct Response {
     is_daily: bool,
     /// If this is a timed mode game. Mutually exclusive with `is_daily`.
     is_timed: bool,
-    /// If this is a genre-specific game, the genre ID. Otherwise `null`.
+    /// If this is a genre-specific game, the genres the track was picked from.
+    /// Otherwise empty.
     ///
     /// Mutually exclusive with `is_daily`.
-    genre: Option<deezer::Genre>,
+    genres: Vec<deezer::Genre>,
+    /// How strongly popular tracks were preferred when picking the track.
+    difficulty: Difficulty,
+    /// If this is an era game, the range of years the track was released in.
+    /// Otherwise `null`.
+    ///
+    /// Mutually exclusive with `is_daily`.
+    era: Option<Era>,
+    /// If this is an artist game, the artist. Otherwise `null`.
+    ///
+    /// Mutually exclusive with `is_daily`, `genres` and `era`.
+    artist: Option<deezer::Artist>,
+    /// If this is an artist game, whether tracks by related artists may be picked.
+    related_artists: bool,
+    /// If this is a playlist game, the playlist. Otherwise `null`.
+    ///
+    /// Mutually exclusive with `is_daily`, `genres`, `era` and `artist`.
+    playlist: Option<deezer::Playlist>,
     /// The guesses (or skips) made so far in this game.
     guesses: Vec<GuessResponse>,
     /// Timing information on the current guess, if this is an ongoing timed game.
@@ -1,5 +1,14 @@
 //! API routes for managing games.
-use crate::{deezer, game, track, ApiError, DbConn, Game, Session, Transaction};
+use crate::{
+    deezer, game,
+    throttle::{self, Throttle},
+    track::{
+        self,
+        pick::{Difficulty, Era},
+    },
+    ApiError, DbConn, Game, Session, Transaction,
+};
+use chrono::{Datelike, Utc};
 use rocket::{get, http::ContentType, post, routes, serde::json::Json};
 use serde::{Deserialize, Serialize};
 
@@ -48,23 +57,160 @@ async fn recent_games(
     Ok(Json(RecentGames { daily, ongoing }))
 }
 
+/// The most genres a game can be restricted to at once.
+const MAX_GENRES: usize = 10;
+
+/// The earliest year an era can start in.
+const MIN_ERA_YEAR: i32 = 1900;
+
+/// The most years an era can cover. Fetching tracks for an era takes a couple of
+/// requests to the music provider per decade.
+const MAX_ERA_YEARS: i32 = 50;
+
 /// The request body for creating a new game.
 #[derive(Deserialize)]
 struct NewGame {
-    /// The genre ID to restrict the game to, or `null` to allow any genre.
+    /// The genre IDs to pick from, or empty to allow any genre.
+    #[serde(default)]
+    genre_ids: Vec<deezer::Id>,
+    /// A shorthand for `genre_ids` with a single genre. Mutually exclusive with
+    /// `genre_ids`.
     genre_id: Option<deezer::Id>,
-    /// Whether the game is a daily game. If it is, `genre_id` and `timed` must
-    /// be `null` and `false` respectively.
+    /// Whether the game is a daily game. If it is, `genre_ids`, `genre_id`, `era`,
+    /// `decade`, `artist_id` and `playlist_id` must be unset and `timed` must be
+    /// `false`.
     #[serde(default)]
     daily: bool,
     /// Whether the game is to be in timed mode.
     #[serde(default)]
     timed: bool,
+    /// How strongly to prefer popular tracks, or `null` for the default. Must be
+    /// `null` for daily games, which are the same for everyone.
+    difficulty: Option<Difficulty>,
+    /// The range of years to restrict the game to, as `start_year` and `end_year`
+    /// (inclusive), or `null` to allow any release date. Must be between 1900 and the
+    /// current year, and cover at most [`MAX_ERA_YEARS`] years.
+    era: Option<Era>,
+    /// A shorthand for an era covering a decade, given by its first year (such as
+    /// `1990`). Mutually exclusive with `era`.
+    decade: Option<i32>,
+    /// The artist to pick one of the top tracks of, or `null` for any artist. Mutually
+    /// exclusive with the genres, `era` and `decade`.
+    artist_id: Option<deezer::Id>,
+    /// Whether to also pick from the top tracks of artists related to `artist_id`.
+    #[serde(default)]
+    related_artists: bool,
+    /// The playlist to pick one of the tracks of, or `null` to not use a playlist.
+    /// Mutually exclusive with the genres, `era`, `decade` and `artist_id`.
+    playlist_id: Option<deezer::Id>,
+}
+
+impl NewGame {
+    /// Get the game mode requested, validating it.
+    fn mode(&self) -> Result<game::Mode, ApiError> {
+        let era = self.era()?;
+        let genre_ids = self.genre_ids()?;
+        if self.daily
+            && (!genre_ids.is_empty()
+                || self.timed
+                || self.difficulty.is_some()
+                || era.is_some()
+                || self.artist_id.is_some()
+                || self.playlist_id.is_some())
+        {
+            return Err(ApiError::bad_request(
+                "daily games cannot be timed or have a genre, difficulty, era, artist or playlist",
+            ));
+        }
+        if self.playlist_id.is_some()
+            && (!genre_ids.is_empty() || era.is_some() || self.artist_id.is_some())
+        {
+            return Err(ApiError::bad_request(
+                "playlist games cannot have a genre, era or artist",
+            ));
+        }
+        if self.artist_id.is_some() && (!genre_ids.is_empty() || era.is_some()) {
+            return Err(ApiError::bad_request(
+                "artist games cannot have a genre or era",
+            ));
+        }
+        if self.related_artists && self.artist_id.is_none() {
+            return Err(ApiError::bad_request(
+                "related artists can only be included in artist games",
+            ));
+        }
+        Ok(game::Mode {
+            daily: self.daily,
+            timed: self.timed,
+            genre_ids,
+            difficulty: self.difficulty.unwrap_or_default(),
+            era,
+            artist_id: self.artist_id,
+            related_artists: self.related_artists,
+            playlist_id: self.playlist_id,
+        })
+    }
+
+    /// Get the genres to restrict the game to, without duplicates, validating the
+    /// request.
+    fn genre_ids(&self) -> Result<Vec<deezer::Id>, ApiError> {
+        if self.genre_id.is_some() && !self.genre_ids.is_empty() {
+            return Err(ApiError::bad_request(
+                "only one of genre_id and genre_ids can be given",
+            ));
+        }
+        let mut genre_ids = Vec::with_capacity(self.genre_ids.len());
+        for genre_id in self.genre_id.iter().chain(&self.genre_ids) {
+            if !genre_ids.contains(genre_id) {
+                genre_ids.push(*genre_id);
+            }
+        }
+        if genre_ids.len() > MAX_GENRES {
+            return Err(ApiError::bad_request("too many genres"));
+        }
+        Ok(genre_ids)
+    }
+
+    /// Get the era to restrict the game to, if any, validating the request.
+    fn era(&self) -> Result<Option<Era>, ApiError> {
+        let era = match (self.era, self.decade) {
+            (Some(_), Some(_)) => {
+                return Err(ApiError::bad_request(
+                    "only one of era and decade can be given",
+                ))
+            }
+            (Some(era), None) => era,
+            (None, Some(decade)) if decade % 10 == 0 => {
+                Era::decade(decade).ok_or(ApiError::bad_request("decade is out of range"))?
+            }
+            (None, Some(_)) => {
+                return Err(ApiError::bad_request(
+                    "decade must be the first year of a decade",
+                ))
+            }
+            (None, None) => return Ok(None),
+        };
+        if era.start_year > era.end_year {
+            return Err(ApiError::bad_request("era must not end before it starts"));
+        }
+        if era.start_year < MIN_ERA_YEAR || era.end_year > Utc::now().year() {
+            return Err(ApiError::bad_request(
+                "era must be between 1900 and the current year",
+            ));
+        }
+        if era.end_year - era.start_year >= MAX_ERA_YEARS {
+            return Err(ApiError::bad_request(
+                "era must not be longer than 50 years",
+            ));
+        }
+        Ok(Some(era))
+    }
 }
 
 /// Begin a new game for the authenticated user.
 #[post("/games", data = "<body>")]
 async fn new_game(
+    _throttle: Throttle<throttle::Game>,
     mut tx: Transaction<'_>,
     auth: Session,
     body: Json<NewGame>,
@@ -73,32 +219,43 @@ async fn new_game(
     if user.ongoing_game_id(&mut tx).await?.is_some() {
         return Err(ApiError::conflict("user already has an ongoing game"));
     }
-    let track_id = if body.daily {
-        if body.genre_id.is_some() || body.timed {
-            return Err(ApiError::bad_request(
-                "daily games cannot be timed or have a genre",
-            ));
-        }
+    let mode = body.mode()?;
+    if !track::ensure_genres(&mut tx, &mode.genre_ids).await? {
+        return Err(ApiError::not_found("no such genre"));
+    }
+    let track_id = if mode.daily {
         if user.daily_game_id(&mut tx).await?.is_some() {
             return Err(ApiError::conflict(
                 "user has already started the daily game today",
             ));
         }
         track::pick::daily(&mut tx).await?
-    } else if let Some(genre_id) = body.genre_id {
-        track::pick::genre(&mut tx, genre_id, user.id).await?
+    } else if let Some(playlist_id) = mode.playlist_id {
+        track::pick::playlist(&mut tx, playlist_id, &user, mode.difficulty)
+            .await?
+            .ok_or(ApiError::not_found("no such playlist"))?
+            .ok_or(ApiError::not_found("playlist has no playable tracks"))?
+    } else if let Some(artist_id) = mode.artist_id {
+        track::pick::artist(
+            &mut tx,
+            artist_id,
+            mode.related_artists,
+            &user,
+            mode.difficulty,
+        )
+        .await?
+        .ok_or(ApiError::not_found("no such artist"))?
+        .ok_or(ApiError::not_found("artist has no playable tracks"))?
+    } else if let Some(era) = mode.era {
+        track::pick::era(&mut tx, era, &mode.genre_ids, &user, mode.difficulty)
+            .await?
+            .ok_or(ApiError::not_found("era has no playable tracks"))?
+    } else if !mode.genre_ids.is_empty() {
+        track::pick::genres(&mut tx, &mode.genre_ids, &user, mode.difficulty).await?
     } else {
-        track::pick::any(&mut tx, user.id).await?
+        track::pick::any(&mut tx, &user, mode.difficulty).await?
     };
-    let game = Game::create(
-        &mut tx,
-        user.id,
-        body.genre_id,
-        body.daily,
-        body.timed,
-        track_id,
-    )
-    .await?;
+    let game = Game::create(&mut tx, user.id, &mode, track_id).await?;
     let game = game.into_response(&mut tx).await?;
     tx.commit().await?;
     Ok(Json(game))
@@ -142,6 +299,7 @@ async fn resign_game(
 /// Submit a guess for the authenticated user's active game.
 #[post("/games/<id>/guesses", data = "<body>")]
 async fn new_guess(
+    _throttle: Throttle<throttle::Game>,
     mut tx: Transaction<'_>,
     auth: Session,
     id: i32,
@@ -190,5 +348,7 @@ async fn get_clip(
         ));
     }
     let bytes = track::clip(&mut tx, game.track_id, start..end).await?;
+    // Getting the clip may have refreshed the track's expired preview URL.
+    tx.commit().await?;
     Ok((ContentType::new("audio", "wav"), bytes))
 }
@@ -20,8 +20,10 @@ mod api_error;
 mod database;
 mod deezer;
 mod game;
+mod provider;
 mod ratelimit;
 mod tasks;
+mod throttle;
 mod track;
 mod user;
 mod web;
@@ -48,13 +50,16 @@ fn rocket() -> _ {
         .merge(("address", &config.address))
         .merge(("port", config.port))
         .merge(("databases.main.url", &config.db_url));
+    provider::init(&config);
     track::init(&config);
     user::init(&config);
+    throttle::init(&config);
     rocket::custom(figment)
         .attach(database::Main::init())
         .attach(AdHoc::try_on_ignite("migrations", database::run_migrations))
         .attach(AdHoc::try_on_ignite("background tasks", tasks::spawn))
         .mount("/api", api_routes())
+        .register("/api", throttle::catchers())
         .mount("/", web::routes(config.dev))
 }
 
@@ -63,6 +68,7 @@ fn api_routes() -> Vec<rocket::Route> {
     let mut routes = game::routes();
     routes.extend(user::routes());
     routes.extend(track::routes());
+    routes.extend(provider::routes());
     routes
 }
 
@@ -92,6 +98,26 @@ pub struct Config {
     /// How long a session token is valid for.
     #[serde(default = "default_session_lifetime")]
     session_lifetime: duration_string::DurationString,
+    /// Which music provider to source tracks, genres and preview audio from (default "deezer").
+    #[serde(default)]
+    provider: provider::Kind,
+    /// Settings for the Deezer provider.
+    #[serde(default)]
+    deezer: deezer::Config,
+    /// Settings for the local library provider (required if `provider` is "local").
+    library: Option<provider::local::Config>,
+    /// Settings for the Subsonic provider (required if `provider` is "subsonic").
+    subsonic: Option<provider::subsonic::Config>,
+    /// Per-client rate limits for API routes.
+    #[serde(default)]
+    throttle: throttle::Config,
+    /// Whether tracks with explicit lyrics may be picked, for users who haven't chosen
+    /// (default true). The daily track is shared by everyone, so always follows this.
+    #[serde(default = "default_allow_explicit")]
+    allow_explicit: bool,
+    /// The country players are in, as an ISO 3166-1 alpha-2 code such as "GB". Tracks
+    /// known not to be available there are never picked or shown in search results.
+    region: Option<String>,
 }
 
 /// Get the default configuration value for the port.
@@ -109,6 +135,11 @@ fn default_session_lifetime() -> duration_string::DurationString {
     duration_string::DurationString::from_string("30d".into()).unwrap()
 }
 
+/// Allow explicit tracks by default.
+const fn default_allow_explicit() -> bool {
+    true
+}
+
 /// Enable dev mode by default in debug builds.
 const fn default_dev_mode() -> bool {
     cfg!(debug_assertions)
@@ -0,0 +1,178 @@
+//! An in-memory music provider for tests.
+//!
+//! The catalog is a small fixed set of tracks in two genres, so that code using
+//! [`super::get`] can be tested without the network.
+use chrono::{Datelike, NaiveDate};
+use eyre::{eyre, Result};
+
+use super::{MusicProvider, PreviewStream};
+use crate::deezer::{Album, Artist, Genre, Id, PartialAlbum, Playlist, Track};
+
+/// The IDs of the tracks in the catalog.
+const TRACK_IDS: std::ops::RangeInclusive<u64> = 1..=8;
+
+/// The ID of the only playlist in the catalog, which has the first three tracks.
+pub const PLAYLIST_ID: Id = Id(1);
+
+/// The ID of a track which can't be streamed.
+pub const UNREADABLE_TRACK_ID: Id = Id(7);
+
+/// The ID of the Pop album, which has the even tracks.
+const POP_ALBUM_ID: Id = Id(100);
+
+/// The ID of the Rock album, which has the odd tracks.
+const ROCK_ALBUM_ID: Id = Id(101);
+
+/// A fake music provider with a fixed catalog.
+///
+/// Tracks 1 to 8 are titled "Song 1" to "Song 8", and a track's rank is its ID times
+/// 1000. Odd tracks are on a Rock album from 1975, and even tracks on a Pop album from
+/// 2005. Track [`UNREADABLE_TRACK_ID`] is not readable.
+pub struct Fake;
+
+/// Create a genre in the catalog.
+fn genre(pop: bool) -> Genre {
+    let (id, name) = if pop { (132, "Pop") } else { (152, "Rock") };
+    Genre {
+        id: Id(id),
+        name: name.into(),
+        picture: String::new(),
+    }
+}
+
+/// Create an album in the catalog, if it exists.
+fn album(id: Id) -> Option<Album> {
+    let pop = match id {
+        POP_ALBUM_ID => true,
+        ROCK_ALBUM_ID => false,
+        _ => return None,
+    };
+    Some(Album {
+        id,
+        title: if pop { "Pop Album" } else { "Rock Album" }.into(),
+        cover: String::new(),
+        link: String::new(),
+        genres: vec![genre(pop)].into(),
+        release_date: NaiveDate::from_ymd_opt(if pop { 2005 } else { 1975 }, 1, 1),
+        record_type: Some("album".into()),
+    })
+}
+
+/// Create a track in the catalog, if it exists.
+fn track(id: Id) -> Option<Track> {
+    let n = *id;
+    if !TRACK_IDS.contains(&n) {
+        return None;
+    }
+    let album = album(if n.is_multiple_of(2) {
+        POP_ALBUM_ID
+    } else {
+        ROCK_ALBUM_ID
+    })?;
+    Some(Track {
+        id,
+        title: format!("Song {n}"),
+        rank: i32::try_from(n * 1000).expect("rank fits in i32"),
+        link: String::new(),
+        preview: format!("fake:{n}"),
+        artist: Artist {
+            id: Id(10 + n % 3),
+            name: format!("Artist {}", n % 3),
+            link: String::new(),
+            picture: String::new(),
+        },
+        album: PartialAlbum {
+            id: album.id,
+            title: album.title,
+            cover: album.cover,
+        },
+        duration: Some(180),
+        explicit_lyrics: Some(false),
+        bpm: None,
+        isrc: None,
+        readable: id != UNREADABLE_TRACK_ID,
+        available_countries: None,
+    })
+}
+
+/// Get the tracks matching a predicate on them and their album, most popular first.
+fn tracks_where(predicate: impl Fn(&Track, &Album) -> bool) -> Vec<Track> {
+    let mut tracks: Vec<_> = TRACK_IDS
+        .filter_map(|id| track(Id(id)))
+        .filter(|track| album(track.album.id).is_some_and(|album| predicate(track, &album)))
+        .collect();
+    tracks.sort_by_key(|track| std::cmp::Reverse(track.rank));
+    tracks
+}
+
+#[rocket::async_trait]
+impl MusicProvider for Fake {
+    async fn chart(&self, genre_id: Id) -> Result<Vec<Track>> {
+        Ok(tracks_where(|_, album| {
+            *genre_id == 0 || album.genres.iter().any(|genre| genre.id == genre_id)
+        }))
+    }
+
+    async fn era_chart(&self, start_year: i32, end_year: i32) -> Result<Vec<Track>> {
+        Ok(tracks_where(|_, album| {
+            album
+                .release_date
+                .is_some_and(|date| (start_year..=end_year).contains(&date.year()))
+        }))
+    }
+
+    async fn playlist(&self, playlist_id: Id) -> Result<Option<Playlist>> {
+        Ok((playlist_id == PLAYLIST_ID).then(|| Playlist {
+            id: PLAYLIST_ID,
+            title: "Fake Playlist".into(),
+            link: String::new(),
+            picture: String::new(),
+        }))
+    }
+
+    async fn playlist_tracks(&self, playlist_id: Id) -> Result<Vec<Track>> {
+        if playlist_id != PLAYLIST_ID {
+            return Ok(Vec::new());
+        }
+        Ok((1..=3).filter_map(|id| track(Id(id))).collect())
+    }
+
+    async fn artist(&self, artist_id: Id) -> Result<Option<Artist>> {
+        Ok(tracks_where(|track, _| track.artist.id == artist_id)
+            .pop()
+            .map(|track| track.artist))
+    }
+
+    async fn artist_top(&self, artist_id: Id) -> Result<Vec<Track>> {
+        Ok(tracks_where(|track, _| track.artist.id == artist_id))
+    }
+
+    async fn related_artists(&self, _artist_id: Id) -> Result<Vec<Artist>> {
+        Ok(Vec::new())
+    }
+
+    async fn genres(&self) -> Result<Vec<Genre>> {
+        Ok(vec![genre(true), genre(false)])
+    }
+
+    async fn album(&self, album_id: Id) -> Result<Album> {
+        album(album_id).ok_or_else(|| eyre!("unknown fake album: {}", *album_id))
+    }
+
+    async fn track(&self, id: Id) -> Result<Option<Track>> {
+        Ok(track(id))
+    }
+
+    async fn track_search(&self, q: &str) -> Result<Vec<Track>> {
+        let q = q.to_lowercase();
+        Ok(tracks_where(|track, _| {
+            format!("{} {}", track.title, track.artist.name)
+                .to_lowercase()
+                .contains(&q)
+        }))
+    }
+
+    async fn track_preview(&self, _preview_url: &str) -> Result<PreviewStream> {
+        Err(eyre!("the fake provider has no preview audio"))
+    }
+}
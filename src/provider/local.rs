@@ -0,0 +1,619 @@
+//! A music provider backed by a directory of audio files.
+//!
+//! The library is scanned once on startup. Tracks, albums, artists and genres are read from
+//! file tags and given IDs derived from their paths and names, so that IDs are stable between
+//! restarts and the rows already in the database stay valid.
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use chrono::{Datelike, NaiveDate};
+use eyre::{eyre, Context, Result};
+use futures::{StreamExt, TryStreamExt};
+use lofty::{
+    picture::PictureType,
+    prelude::{Accessor, AudioFile, ItemKey, TaggedFileExt},
+    probe::Probe,
+    tag::Tag,
+};
+use rocket::{fs::NamedFile, get, http::Status, routes, tokio::fs};
+use serde::Deserialize;
+
+use super::{stable_id, MusicProvider, PreviewStream};
+use crate::deezer::{Album, Artist, Genre, Id, PartialAlbum, Playlist, Track};
+
+/// The directory extracted cover art is stored in, set on startup.
+static COVERS_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// File extensions (lowercase) of audio files we can read.
+const EXTENSIONS: [&str; 2] = ["mp3", "flac"];
+
+/// Local files have no popularity data, so every track gets the same rank.
+const RANK: i32 = 1;
+
+/// The genre given to albums with no genre tag, so that they can still be picked.
+const FALLBACK_GENRE: &str = "Other";
+
+/// Config options for the local library provider.
+#[derive(Debug, Deserialize)]
+pub struct Config {
+    /// The directory to scan (recursively) for music files.
+    dir: PathBuf,
+}
+
+/// A music library read from a directory of audio files.
+pub struct Library {
+    /// All tracks in the library, by ID.
+    tracks: HashMap<Id, Track>,
+    /// All albums in the library, by ID.
+    albums: HashMap<Id, Album>,
+    /// All genres in the library, in order of first appearance.
+    genres: Vec<Genre>,
+    /// The key each ID was derived from, by kind of object, to detect collisions.
+    keys: HashMap<(&'static str, Id), String>,
+}
+
+impl Library {
+    /// Scan the configured directory and build the library.
+    ///
+    /// This uses blocking IO, and so should only be called on startup. Files which
+    /// cannot be read are skipped with a warning.
+    pub fn scan(config: &Config, media_dir: &Path) -> Result<Self> {
+        let covers_dir = media_dir.join("covers");
+        std::fs::create_dir_all(&covers_dir).wrap_err("error creating covers directory")?;
+        COVERS_DIR
+            .set(covers_dir)
+            .map_err(|_| eyre!("local library must only be scanned once"))?;
+        let library = Self::scan_dir(&config.dir)?;
+        eprintln!(
+            "scanned local library: {} tracks, {} albums, {} genres",
+            library.tracks.len(),
+            library.albums.len(),
+            library.genres.len(),
+        );
+        Ok(library)
+    }
+
+    /// Build the library from the audio files in a directory, once the covers directory
+    /// has been set.
+    fn scan_dir(dir: &Path) -> Result<Self> {
+        let mut library = Self {
+            tracks: HashMap::new(),
+            albums: HashMap::new(),
+            genres: Vec::new(),
+            keys: HashMap::new(),
+        };
+        let mut paths = Vec::new();
+        find_audio_files(dir, &mut paths)?;
+        paths.sort();
+        for path in paths {
+            if let Err(e) = library.add_file(dir, &path) {
+                eprintln!("skipping {}: {e:?}", path.display());
+            }
+        }
+        Ok(library)
+    }
+
+    /// Derive the ID of an object from a key unique among objects of its kind.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the ID was already derived from a different key, rather
+    /// than mixing up the two objects.
+    fn id(&mut self, kind: &'static str, key: &str) -> Result<Id> {
+        let id = stable_id(kind, key);
+        match self.keys.entry((kind, id)) {
+            Entry::Vacant(entry) => {
+                entry.insert(key.into());
+            }
+            Entry::Occupied(entry) if entry.get() != key => {
+                return Err(eyre!(
+                    "{kind} {key:?} has the same ID ({id}) as {:?}",
+                    entry.get()
+                ));
+            }
+            Entry::Occupied(_) => {}
+        }
+        Ok(id)
+    }
+
+    /// Read the tags from an audio file and add it to the library.
+    ///
+    /// The file type is detected from the contents rather than trusting the extension.
+    fn add_file(&mut self, root: &Path, path: &Path) -> Result<()> {
+        let tagged = Probe::open(path)
+            .and_then(|probe| probe.guess_file_type().map_err(Into::into))
+            .and_then(Probe::read)
+            .wrap_err("error reading tags")?;
+        let empty = Tag::new(tagged.primary_tag_type());
+        let tag = tagged
+            .primary_tag()
+            .or_else(|| tagged.first_tag())
+            .unwrap_or(&empty);
+        let relative = path.strip_prefix(root).unwrap_or(path).to_string_lossy();
+        let title = tag.title().map_or_else(
+            || {
+                path.file_stem()
+                    .map_or_else(|| relative.to_string(), |s| s.to_string_lossy().into())
+            },
+            Into::into,
+        );
+        let artist_name = tag
+            .artist()
+            .map_or_else(|| "Unknown artist".into(), String::from);
+        let album_artist = tag
+            .get_string(ItemKey::AlbumArtist)
+            .unwrap_or(&artist_name)
+            .to_string();
+        let album_title = tag
+            .album()
+            .map_or_else(|| "Unknown album".into(), String::from);
+        let album_id = self.id("album", &format!("{album_artist}\0{album_title}"))?;
+        let artist_id = self.id("artist", &artist_name)?;
+        let track_id = self.id("track", &relative)?;
+        if !self.albums.contains_key(&album_id) {
+            let album = self.new_album(album_id, album_title, tag)?;
+            self.albums.insert(album_id, album);
+        }
+        let album = &self.albums[&album_id];
+        let duration = tagged.properties().duration();
+        let track = Track {
+            id: track_id,
+            title,
+            rank: RANK,
+            link: String::new(),
+            preview: path.to_string_lossy().into(),
+            artist: Artist {
+                id: artist_id,
+                name: artist_name,
+                link: String::new(),
+                picture: album.cover.clone(),
+            },
+            album: PartialAlbum {
+                id: album.id,
+                title: album.title.clone(),
+                cover: album.cover.clone(),
+            },
+            duration: (!duration.is_zero())
+                .then(|| i32::try_from(duration.as_secs()).unwrap_or(i32::MAX)),
+            // tags have no standard way to say this
+            explicit_lyrics: None,
+            bpm: tag
+                .get_string(ItemKey::Bpm)
+                .or_else(|| tag.get_string(ItemKey::IntegerBpm))
+                .and_then(|bpm| bpm.trim().parse().ok())
+                .filter(|bpm| *bpm > 0.0),
+            isrc: tag.get_string(ItemKey::Isrc).map(String::from),
+            readable: true,
+            available_countries: None,
+        };
+        self.tracks.insert(track.id, track);
+        Ok(())
+    }
+
+    /// Create an album from the tags of its first track, registering its genres
+    /// and extracting cover art.
+    fn new_album(&mut self, id: Id, title: String, tag: &Tag) -> Result<Album> {
+        let cover = save_cover(id, tag)?.unwrap_or_default();
+        let mut names = tag
+            .genre()
+            .map(|genres| {
+                genres
+                    .split(['/', ';', ','])
+                    .map(str::trim)
+                    .filter(|name| !name.is_empty())
+                    .map(String::from)
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        if names.is_empty() {
+            names.push(FALLBACK_GENRE.into());
+        }
+        let mut genres: Vec<Genre> = Vec::new();
+        for name in names {
+            let genre_id = self.id("genre", &name.to_lowercase())?;
+            // Names which only differ in case are the same genre.
+            if genres.iter().any(|genre| genre.id == genre_id) {
+                continue;
+            }
+            let genre = if let Some(genre) = self.genres.iter().find(|g| g.id == genre_id) {
+                genre.clone()
+            } else {
+                let genre = Genre {
+                    id: genre_id,
+                    name,
+                    picture: cover.clone(),
+                };
+                self.genres.push(genre.clone());
+                genre
+            };
+            genres.push(genre);
+        }
+        Ok(Album {
+            id,
+            title,
+            cover,
+            link: String::new(),
+            genres: genres.into(),
+            release_date: tag.date().and_then(|date| {
+                NaiveDate::from_ymd_opt(
+                    date.year.into(),
+                    date.month.unwrap_or(1).into(),
+                    date.day.unwrap_or(1).into(),
+                )
+            }),
+            record_type: None,
+        })
+    }
+
+    /// Get all tracks matching a predicate, in a stable order.
+    fn tracks_where(&self, mut predicate: impl FnMut(&Track) -> bool) -> Vec<Track> {
+        let mut tracks: Vec<_> = self
+            .tracks
+            .values()
+            .filter(|track| predicate(track))
+            .cloned()
+            .collect();
+        tracks.sort_by(|a, b| a.preview.cmp(&b.preview));
+        tracks
+    }
+}
+
+#[rocket::async_trait]
+impl MusicProvider for Library {
+    /// The "chart" for a genre is every track in it, since we have no popularity data.
+    async fn chart(&self, genre_id: Id) -> Result<Vec<Track>> {
+        if *genre_id == 0 {
+            return Ok(self.tracks_where(|_| true));
+        }
+        Ok(self.tracks_where(|track| {
+            self.albums[&track.album.id]
+                .genres
+                .iter()
+                .any(|genre| genre.id == genre_id)
+        }))
+    }
+
+    /// Like [`Self::chart`], every track released in the range.
+    async fn era_chart(&self, start_year: i32, end_year: i32) -> Result<Vec<Track>> {
+        Ok(self.tracks_where(|track| {
+            self.albums[&track.album.id]
+                .release_date
+                .is_some_and(|date| (start_year..=end_year).contains(&date.year()))
+        }))
+    }
+
+    /// Playlist files aren't read, so there are no playlists.
+    async fn playlist(&self, _playlist_id: Id) -> Result<Option<Playlist>> {
+        Ok(None)
+    }
+
+    async fn playlist_tracks(&self, _playlist_id: Id) -> Result<Vec<Track>> {
+        Ok(Vec::new())
+    }
+
+    async fn artist(&self, artist_id: Id) -> Result<Option<Artist>> {
+        Ok(self
+            .tracks
+            .values()
+            .find(|track| track.artist.id == artist_id)
+            .map(|track| track.artist.clone()))
+    }
+
+    /// Every track by the artist, since we have no popularity data.
+    async fn artist_top(&self, artist_id: Id) -> Result<Vec<Track>> {
+        Ok(self.tracks_where(|track| track.artist.id == artist_id))
+    }
+
+    /// File tags say nothing about which artists are similar.
+    async fn related_artists(&self, _artist_id: Id) -> Result<Vec<Artist>> {
+        Ok(Vec::new())
+    }
+
+    async fn genres(&self) -> Result<Vec<Genre>> {
+        Ok(self.genres.clone())
+    }
+
+    async fn album(&self, album_id: Id) -> Result<Album> {
+        self.albums
+            .get(&album_id)
+            .cloned()
+            .ok_or_else(|| eyre!("no such album in local library: {album_id}"))
+    }
+
+    async fn track(&self, id: Id) -> Result<Option<Track>> {
+        Ok(self.tracks.get(&id).cloned())
+    }
+
+    /// Every word of the query must appear in either the track title or the artist name.
+    async fn track_search(&self, q: &str) -> Result<Vec<Track>> {
+        let words: Vec<_> = q.split_whitespace().map(str::to_lowercase).collect();
+        Ok(self.tracks_where(|track| {
+            let haystack = format!("{} {}", track.title, track.artist.name).to_lowercase();
+            words.iter().all(|word| haystack.contains(word))
+        }))
+    }
+
+    async fn track_preview(&self, preview_url: &str) -> Result<PreviewStream> {
+        let file = fs::File::open(preview_url)
+            .await
+            .wrap_err("error opening a local music file")?;
+        Ok(tokio_util::io::ReaderStream::new(file)
+            .map_err(|e| eyre::Report::new(e).wrap_err("error reading a local music file"))
+            .boxed())
+    }
+}
+
+/// Recursively collect the paths of all audio files in a directory.
+fn find_audio_files(dir: &Path, paths: &mut Vec<PathBuf>) -> Result<()> {
+    let entries = std::fs::read_dir(dir)
+        .wrap_err_with(|| format!("error reading music directory {}", dir.display()))?;
+    for entry in entries {
+        let path = entry
+            .wrap_err("error reading music directory entry")?
+            .path();
+        if path.is_dir() {
+            find_audio_files(&path, paths)?;
+        } else if path
+            .extension()
+            .is_some_and(|ext| EXTENSIONS.contains(&ext.to_string_lossy().to_lowercase().as_str()))
+        {
+            paths.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Extract the cover art from a tag (if any) to the covers directory, returning its URL.
+fn save_cover(album_id: Id, tag: &Tag) -> Result<Option<String>> {
+    let Some(picture) = tag
+        .get_picture_type(PictureType::CoverFront)
+        .or_else(|| tag.pictures().first())
+    else {
+        return Ok(None);
+    };
+    let ext = picture
+        .mime_type()
+        .and_then(|mime| mime.ext())
+        .unwrap_or("jpg");
+    let file = format!("{album_id}.{ext}");
+    let path = COVERS_DIR
+        .get()
+        .expect("covers directory used before initialisation")
+        .join(&file);
+    if !path.exists() {
+        std::fs::write(&path, picture.data()).wrap_err("error saving cover art")?;
+    }
+    Ok(Some(format!("/api/library/covers/{file}")))
+}
+
+/// Collect API routes for serving local library data.
+pub fn routes() -> Vec<rocket::Route> {
+    routes![cover]
+}
+
+/// Serve cover art extracted from the local library.
+#[get("/library/covers/<file>")]
+async fn cover(file: &str) -> Result<NamedFile, Status> {
+    let dir = COVERS_DIR.get().ok_or(Status::NotFound)?;
+    if file.contains(['/', '\\']) || file.starts_with('.') {
+        return Err(Status::NotFound);
+    }
+    NamedFile::open(dir.join(file))
+        .await
+        .map_err(|_| Status::NotFound)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lofty::{
+        config::WriteOptions,
+        picture::{MimeType, Picture},
+        prelude::TagExt,
+        tag::TagType,
+    };
+
+    /// Cover art for the tagged tracks.
+    const COVER: &[u8] = b"\x89PNG fake image";
+
+    /// The bytes of a silent MP3 file: a few 128 kbps, 44.1 kHz MPEG-1 Layer III frames.
+    fn mp3() -> Vec<u8> {
+        let mut frame = vec![0; 417];
+        frame[..4].copy_from_slice(&[0xFF, 0xFB, 0x90, 0xC4]);
+        frame.repeat(4)
+    }
+
+    /// The bytes of a FLAC file with just a `STREAMINFO` block (44.1 kHz, 16 bit stereo).
+    fn flac() -> Vec<u8> {
+        let mut data = b"fLaC\x80\0\0\x22".to_vec();
+        data.extend(4096_u16.to_be_bytes().repeat(2));
+        data.extend([0; 6]);
+        data.extend((44_100_u64 << 44 | 1 << 41 | 15 << 36).to_be_bytes());
+        data.extend([0; 16]);
+        data
+    }
+
+    /// Write an audio file under a directory, with tags if given.
+    fn write(dir: &Path, name: &str, data: &[u8], tag: Option<Tag>) {
+        let path = dir.join(name);
+        std::fs::create_dir_all(path.parent().expect("path should have a parent"))
+            .expect("should create directories");
+        std::fs::write(&path, data).expect("should write audio file");
+        if let Some(tag) = tag {
+            tag.save_to_path(&path, WriteOptions::default())
+                .expect("should write tags");
+        }
+    }
+
+    /// Build a tag with a title, artist, album and genre.
+    fn tag(tag_type: TagType, title: &str, artist: &str, album: &str, genre: &str) -> Tag {
+        let mut tag = Tag::new(tag_type);
+        tag.set_title(title.into());
+        tag.set_artist(artist.into());
+        tag.set_album(album.into());
+        tag.set_genre(genre.into());
+        tag
+    }
+
+    /// Create an empty library directory, unique to a test.
+    fn library_dir(test: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("beatdrop-library-{}-{test}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).expect("should create library directory");
+        dir
+    }
+
+    /// Scan a library directory.
+    fn scan(dir: &Path) -> Library {
+        COVERS_DIR.get_or_init(|| library_dir("covers"));
+        Library::scan_dir(dir).expect("scan should succeed")
+    }
+
+    /// Get the track from a file in the library.
+    fn track<'a>(library: &'a Library, relative: &str) -> &'a Track {
+        &library.tracks[&stable_id("track", relative)]
+    }
+
+    #[test]
+    fn reads_tags() {
+        let dir = library_dir("tags");
+        let mut mp3_tag = tag(TagType::Id3v2, "Song", "Singer", "Record", "Rock; Pop");
+        mp3_tag.insert_text(ItemKey::AlbumArtist, "Band".into());
+        mp3_tag.insert_text(ItemKey::RecordingDate, "1999".into());
+        mp3_tag.insert_text(ItemKey::IntegerBpm, "120".into());
+        mp3_tag.push_picture(
+            Picture::unchecked(COVER.to_vec())
+                .pic_type(PictureType::CoverFront)
+                .mime_type(MimeType::Png)
+                .build(),
+        );
+        write(&dir, "song.mp3", &mp3(), Some(mp3_tag));
+        let flac_tag = tag(TagType::VorbisComments, "Other", "Singer", "Record", "Jazz");
+        write(&dir, "other.flac", &flac(), Some(flac_tag));
+        let library = scan(&dir);
+
+        let song = track(&library, "song.mp3");
+        assert_eq!(song.title, "Song");
+        assert_eq!(song.artist.name, "Singer");
+        assert_eq!(song.album.title, "Record");
+        assert_eq!(song.bpm, Some(120.0));
+        let cover = format!("/api/library/covers/{}.png", song.album.id);
+        assert_eq!(song.album.cover, cover);
+        let saved = COVERS_DIR.get().expect("covers dir should be set");
+        assert_eq!(
+            std::fs::read(saved.join(format!("{}.png", song.album.id)))
+                .ok()
+                .as_deref(),
+            Some(COVER)
+        );
+        let album = &library.albums[&song.album.id];
+        assert_eq!(album.release_date, NaiveDate::from_ymd_opt(1999, 1, 1));
+        let genres: Vec<_> = album.genres.iter().map(|g| g.name.as_str()).collect();
+        assert_eq!(genres, ["Rock", "Pop"]);
+
+        let other = track(&library, "other.flac");
+        assert_eq!(other.title, "Other");
+        assert_ne!(
+            other.album.id, song.album.id,
+            "albums should be told apart by album artist"
+        );
+        assert_eq!(other.artist.id, song.artist.id);
+    }
+
+    #[test]
+    fn derives_ids_from_paths_and_names() {
+        let dir = library_dir("ids");
+        let first = tag(TagType::Id3v2, "One", "Band", "Record", "rock");
+        let second = tag(TagType::Id3v2, "Two", "Band", "Record", "Rock");
+        let other = tag(TagType::Id3v2, "Three", "Band", "Other", "ROCK");
+        write(&dir, "a/one.mp3", &mp3(), Some(first));
+        write(&dir, "b/two.mp3", &mp3(), Some(second));
+        write(&dir, "b/three.mp3", &mp3(), Some(other));
+        write(&dir, "untagged.mp3", &mp3(), None);
+        let library = scan(&dir);
+
+        let one = track(&library, "a/one.mp3");
+        let two = track(&library, "b/two.mp3");
+        assert_eq!(one.artist.id, stable_id("artist", "Band"));
+        assert_eq!(one.album.id, stable_id("album", "Band\0Record"));
+        assert_eq!(one.album.id, two.album.id);
+        assert_ne!(one.album.id, track(&library, "b/three.mp3").album.id);
+        let rock = stable_id("genre", "rock");
+        assert_eq!(library.genres.len(), 2, "genres should ignore case");
+        assert_eq!(library.genres[0].id, rock);
+        assert_eq!(
+            library.genres[0].name, "rock",
+            "the first spelling should be kept"
+        );
+
+        let untagged = track(&library, "untagged.mp3");
+        assert_eq!(untagged.title, "untagged");
+        assert_eq!(untagged.artist.name, "Unknown artist");
+        let genres = &library.albums[&untagged.album.id].genres;
+        assert_eq!(genres[0].name, FALLBACK_GENRE);
+    }
+
+    #[test]
+    fn sniffs_file_types() {
+        let dir = library_dir("sniff");
+        let misnamed = tag(
+            TagType::VorbisComments,
+            "Misnamed",
+            "Band",
+            "Record",
+            "Rock",
+        );
+        write(&dir, "misnamed.mp3", &flac(), None);
+        // Tags are written according to the real file type.
+        misnamed
+            .save_to_path(dir.join("misnamed.mp3"), WriteOptions::default())
+            .expect("should write tags");
+        write(&dir, "LOUD.FLAC", &flac(), None);
+        write(&dir, "notes.txt", &mp3(), None);
+        write(&dir, "broken.mp3", b"not audio at all", None);
+        let library = scan(&dir);
+
+        assert_eq!(track(&library, "misnamed.mp3").title, "Misnamed");
+        assert_eq!(track(&library, "LOUD.FLAC").title, "LOUD");
+        assert_eq!(
+            library.tracks.len(),
+            2,
+            "other extensions and unreadable files should be skipped"
+        );
+    }
+
+    #[test]
+    fn detects_id_collisions() {
+        let dir = library_dir("collisions");
+        let song = tag(TagType::Id3v2, "Song", "Band", "Record", "Rock");
+        write(&dir, "song.mp3", &mp3(), Some(song));
+        let mut library = scan(&dir);
+        assert!(
+            library.add_file(&dir, &dir.join("song.mp3")).is_ok(),
+            "deriving an ID from the same key again is fine"
+        );
+        let empty = library_dir("collisions-empty");
+        for (kind, key) in [
+            ("track", "song.mp3"),
+            ("artist", "Band"),
+            ("album", "Band\0Record"),
+            ("genre", "rock"),
+        ] {
+            let mut library = scan(&empty);
+            library
+                .keys
+                .insert((kind, stable_id(kind, key)), "something else".into());
+            let error = library
+                .add_file(&dir, &dir.join("song.mp3"))
+                .expect_err("a colliding ID should be an error");
+            assert!(
+                error.to_string().contains("has the same ID"),
+                "unexpected error for {kind}: {error:?}"
+            );
+        }
+    }
+}
@@ -0,0 +1,239 @@
+//! Pluggable sources of music data.
+//!
+//! Everything that needs catalog data (charts, genres, albums, tracks, search results) or
+//! preview audio goes through the [`MusicProvider`] configured at startup, rather than
+//! talking to a specific service directly.
+use std::{fmt, pin::Pin, sync::OnceLock, time::Duration};
+
+use eyre::Result;
+use futures::Stream;
+use rocket::http::hyper::body::Bytes;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::deezer::{self, Album, Artist, Genre, Id, Playlist, Track};
+
+#[cfg(test)]
+pub mod fake;
+pub mod local;
+pub mod subsonic;
+
+/// The provider selected on startup.
+static PROVIDER: OnceLock<Box<dyn MusicProvider>> = OnceLock::new();
+
+#[cfg(test)]
+rocket::tokio::task_local! {
+    /// A provider overriding the global one within a task, see [`scope`].
+    static SCOPED: &'static dyn MusicProvider;
+}
+
+/// A stream of bytes of preview audio (MP3).
+pub type PreviewStream = Pin<Box<dyn Stream<Item = Result<Bytes>> + Send>>;
+
+/// A source of music data and preview audio.
+///
+/// Data is returned using the types from the [`deezer`] module, which double as the
+/// data model for the music tables in the database.
+#[rocket::async_trait]
+pub trait MusicProvider: Send + Sync {
+    /// Fetch the "chart" (a list of popular tracks) for a given genre.
+    /// The genre with ID 0 is "all genres".
+    async fn chart(&self, genre_id: Id) -> Result<Vec<Track>>;
+
+    /// Fetch a list of popular tracks released between the given years (inclusive).
+    ///
+    /// This is best effort: some tracks may have been released outside the range, and
+    /// tracks are only picked for a game if their album's release date is in it.
+    async fn era_chart(&self, start_year: i32, end_year: i32) -> Result<Vec<Track>>;
+
+    /// Get a playlist by its ID, returning None if it was not found.
+    async fn playlist(&self, playlist_id: Id) -> Result<Option<Playlist>>;
+
+    /// Fetch the tracks in a playlist, in order. Long playlists may be cut short.
+    async fn playlist_tracks(&self, playlist_id: Id) -> Result<Vec<Track>>;
+
+    /// Get an artist by its ID, returning None if it was not found.
+    async fn artist(&self, artist_id: Id) -> Result<Option<Artist>>;
+
+    /// Fetch the most popular tracks by an artist. This is empty if the artist doesn't
+    /// exist.
+    async fn artist_top(&self, artist_id: Id) -> Result<Vec<Track>>;
+
+    /// Fetch artists similar to the given one, most similar first. This may be empty if
+    /// the provider has no such data.
+    async fn related_artists(&self, artist_id: Id) -> Result<Vec<Artist>>;
+
+    /// Get a list of common genres.
+    async fn genres(&self) -> Result<Vec<Genre>>;
+
+    /// Get an album by its ID.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the album does not exist, or if fetching it fails for
+    /// another reason.
+    async fn album(&self, album_id: Id) -> Result<Album>;
+
+    /// Fetch a track by ID, returning None if it was not found.
+    async fn track(&self, id: Id) -> Result<Option<Track>>;
+
+    /// Fetch a track by ID like [`Self::track`], but never from a cache, so that its
+    /// data (such as its preview URL, or whether it still exists) is current.
+    async fn track_uncached(&self, id: Id) -> Result<Option<Track>> {
+        self.track(id).await
+    }
+
+    /// Search for a track by name or artist.
+    async fn track_search(&self, q: &str) -> Result<Vec<Track>>;
+
+    /// Stream the preview audio referenced by a track's `preview` field.
+    ///
+    /// Returns a [`PreviewExpired`] error if the provider no longer accepts the preview
+    /// URL, in which case the track should be fetched again for a new one.
+    async fn track_preview(&self, preview_url: &str) -> Result<PreviewStream>;
+
+    /// Delete any cached responses which have expired, for providers with a disk cache.
+    async fn prune_cache(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// The error returned when a provider's upstream service is temporarily unavailable,
+/// so that the request should be retried later rather than treated as a bug.
+#[derive(Debug)]
+pub struct Unavailable {
+    /// How long the client should wait before trying again.
+    pub retry_after: Duration,
+}
+
+impl fmt::Display for Unavailable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "music provider unavailable, retry after {}s",
+            self.retry_after.as_secs()
+        )
+    }
+}
+
+impl std::error::Error for Unavailable {}
+
+impl Unavailable {
+    /// Whether an error was ultimately caused by the provider being unavailable.
+    pub fn caused(e: &eyre::Report) -> bool {
+        e.root_cause().is::<Self>()
+    }
+}
+
+/// The error returned when a track's preview URL has expired.
+#[derive(Debug)]
+pub struct PreviewExpired;
+
+impl fmt::Display for PreviewExpired {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "track preview URL has expired")
+    }
+}
+
+impl std::error::Error for PreviewExpired {}
+
+/// Which music provider to use.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    /// The public [Deezer API](https://developers.deezer.com/api).
+    #[default]
+    Deezer,
+    /// A directory of audio files, see [`local`].
+    Local,
+    /// A self-hosted Subsonic-compatible server, see [`subsonic`].
+    Subsonic,
+}
+
+/// Set up the music provider selected in the given config.
+///
+/// Must only be called once.
+pub fn init(config: &crate::Config) {
+    let provider: Box<dyn MusicProvider> = match config.provider {
+        Kind::Deezer => Box::new(
+            deezer::Deezer::new(&config.deezer, &config.media_dir)
+                .expect("failed to set up Deezer client"),
+        ),
+        Kind::Local => {
+            let library = config
+                .library
+                .as_ref()
+                .expect("`library` must be configured to use the local provider");
+            Box::new(
+                local::Library::scan(library, &config.media_dir)
+                    .expect("failed to scan local music library"),
+            )
+        }
+        Kind::Subsonic => {
+            let subsonic = config
+                .subsonic
+                .as_ref()
+                .expect("`subsonic` must be configured to use the Subsonic provider");
+            Box::new(
+                subsonic::Subsonic::new(subsonic, &config.media_dir)
+                    .expect("failed to set up Subsonic provider"),
+            )
+        }
+    };
+    PROVIDER
+        .set(provider)
+        .map_err(|_| ())
+        .expect("provider::init must only be called once");
+}
+
+/// Use the [`fake`] provider, for tests.
+///
+/// Unlike [`init`], this may be called any number of times.
+#[cfg(test)]
+pub fn use_fake() {
+    PROVIDER.get_or_init(|| Box::new(fake::Fake));
+}
+
+/// Run a future with a different music provider than the global one, for tests which
+/// need a particular provider.
+///
+/// The override only applies within the current task, not to spawned tasks.
+#[cfg(test)]
+pub async fn scope<F: std::future::Future>(
+    provider: &'static dyn MusicProvider,
+    future: F,
+) -> F::Output {
+    SCOPED.scope(provider, future).await
+}
+
+/// Get the configured music provider.
+pub fn get() -> &'static dyn MusicProvider {
+    #[cfg(test)]
+    if let Ok(provider) = SCOPED.try_with(|provider| *provider) {
+        return provider;
+    }
+    PROVIDER
+        .get()
+        .expect("music provider used before initialisation")
+        .as_ref()
+}
+
+/// Collect API routes provided by music providers.
+pub fn routes() -> Vec<rocket::Route> {
+    let mut routes = local::routes();
+    routes.extend(subsonic::routes());
+    routes
+}
+
+/// Derive a stable ID from a kind of object and a key unique among objects of that kind.
+///
+/// This is for providers whose own identifiers are not integers. IDs are 53 bits, the
+/// most which survive being sent to the web client as JSON numbers (JavaScript doubles).
+fn stable_id(kind: &str, key: &str) -> Id {
+    let hash = Sha256::new()
+        .chain_update(kind)
+        .chain_update([0])
+        .chain_update(key)
+        .finalize();
+    Id(u64::from_be_bytes(hash[..8].try_into().expect("slice is 8 bytes")) >> 11)
+}
@@ -0,0 +1,1037 @@
+//! A music provider for self-hosted servers speaking the
+//! [Subsonic API](http://www.subsonic.org/pages/api.jsp) (Navidrome, Airsonic, Gonic, ...).
+//!
+//! Subsonic identifiers are arbitrary strings, so each one is mapped to a stable 53 bit ID.
+//! The mapping is persisted under the media directory so that IDs already stored in the
+//! database can be resolved after a restart.
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet},
+    path::PathBuf,
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+
+use chrono::NaiveDate;
+use duration_string::DurationString;
+use eyre::{eyre, Context, Result};
+use futures::{StreamExt, TryStreamExt};
+use md5::{Digest, Md5};
+use rocket::{
+    get,
+    http::{ContentType, Status},
+    routes,
+    serde::json::serde_json,
+};
+use serde::{de::DeserializeOwned, Deserialize};
+
+use super::{stable_id, MusicProvider, PreviewStream};
+use crate::{
+    deezer::{Album, Artist, Genre, Id, PartialAlbum, Playlist, Track},
+    throttle::{self, Throttle},
+};
+
+/// The server connection, shared with the cover art route.
+static SERVER: OnceLock<Server> = OnceLock::new();
+
+/// The API version we claim to speak (the one which introduced `search3`).
+const API_VERSION: &str = "1.16.1";
+
+/// The client name sent to the server.
+const CLIENT_NAME: &str = "beatdrop";
+
+/// How many tracks to request for a genre "chart".
+const CHART_SIZE: u32 = 50;
+
+/// How many tracks to request for a search.
+const SEARCH_SIZE: u32 = 20;
+
+/// How many tracks to request for an artist's top tracks.
+const ARTIST_TOP_SIZE: u32 = 50;
+
+/// How many similar artists to request.
+const RELATED_ARTISTS_SIZE: u32 = 10;
+
+/// The Subsonic error code for a missing resource.
+const NOT_FOUND: u32 = 70;
+
+/// Config options for the Subsonic provider.
+#[derive(Debug, Deserialize)]
+pub struct Config {
+    /// The base URL of the server, e.g. `https://music.example.com`.
+    url: String,
+    /// The username to log in with.
+    username: String,
+    /// The password to log in with.
+    password: String,
+    /// How long to wait for a whole request, including streaming a track, before
+    /// giving up.
+    #[serde(default = "default_timeout")]
+    timeout: DurationString,
+}
+
+/// The default request timeout.
+fn default_timeout() -> DurationString {
+    Duration::from_secs(30).into()
+}
+
+/// A connection to a Subsonic server.
+struct Server {
+    /// The HTTP client used to make requests.
+    client: reqwest::Client,
+    /// The base URL of the server, without a trailing slash.
+    url: String,
+    /// The username to log in with.
+    username: String,
+    /// The password to log in with.
+    password: String,
+}
+
+impl Server {
+    /// Build a request for an API method, with authentication parameters.
+    fn request(&self, method: &str) -> reqwest::RequestBuilder {
+        let salt = hex(&rand::random::<[u8; 8]>());
+        let token = hex(&Md5::new()
+            .chain_update(&self.password)
+            .chain_update(&salt)
+            .finalize());
+        self.client
+            .get(format!("{}/rest/{method}", self.url))
+            .query(&[
+                ("u", self.username.as_str()),
+                ("t", &token),
+                ("s", &salt),
+                ("v", API_VERSION),
+                ("c", CLIENT_NAME),
+                ("f", "json"),
+            ])
+    }
+
+    /// Call an API method, returning `None` if the server reports the resource is missing.
+    async fn try_call<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        query: &[(&str, &str)],
+    ) -> Result<Option<T>> {
+        let body = self
+            .request(method)
+            .query(query)
+            .send()
+            .await
+            .wrap_err("error sending request to Subsonic server")?
+            .error_for_status()
+            .wrap_err("Subsonic server returned an HTTP error")?
+            .bytes()
+            .await
+            .wrap_err("error reading Subsonic response")?;
+        let status: Envelope<ResponseStatus> =
+            serde_json::from_slice(&body).wrap_err("error deserialising Subsonic response")?;
+        if let Some(error) = status.response.error {
+            if error.code == NOT_FOUND {
+                return Ok(None);
+            }
+            return Err(eyre!(
+                "Subsonic server returned an error ({}): {}",
+                error.code,
+                error.message
+            ));
+        }
+        let data: Envelope<T> =
+            serde_json::from_slice(&body).wrap_err("error deserialising Subsonic response")?;
+        Ok(Some(data.response))
+    }
+
+    /// Call an API method, returning an error if the resource was not found.
+    async fn call<T: DeserializeOwned>(&self, method: &str, query: &[(&str, &str)]) -> Result<T> {
+        self.try_call(method, query)
+            .await?
+            .ok_or_else(|| eyre!("requested resource not found"))
+    }
+}
+
+/// A Subsonic server used as a music provider.
+pub struct Subsonic {
+    /// Where the ID mapping is persisted.
+    ids_path: PathBuf,
+    /// Maps our IDs to Subsonic identifiers (or genre names, for genres).
+    ids: Mutex<IdMap>,
+    /// How many entries the ID map had had added when it was last saved, which is
+    /// locked for the duration of each save so that only one is written at a time.
+    saved_changes: rocket::tokio::sync::Mutex<u64>,
+}
+
+/// The mapping from our IDs to Subsonic identifiers.
+#[derive(Default, serde::Serialize, Deserialize)]
+struct IdMap {
+    /// The mapping itself.
+    ids: HashMap<Id, String>,
+    /// How many entries have been added since startup, to tell if there are any which
+    /// have not been saved yet.
+    #[serde(skip)]
+    changes: u64,
+}
+
+impl Subsonic {
+    /// Set up the provider, loading any previously saved ID mapping.
+    pub fn new(config: &Config, media_dir: &std::path::Path) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(config.timeout.into())
+            .build()
+            .wrap_err("error building Subsonic HTTP client")?;
+        let server = Server {
+            client,
+            url: config.url.trim_end_matches('/').into(),
+            username: config.username.clone(),
+            password: config.password.clone(),
+        };
+        SERVER
+            .set(server)
+            .map_err(|_| eyre!("Subsonic provider must only be set up once"))?;
+        let ids_path = media_dir.join("subsonic_ids.json");
+        // fine to use blocking API here, only called on startup
+        let ids = if ids_path.exists() {
+            let data = std::fs::read(&ids_path).wrap_err("error reading Subsonic ID map")?;
+            serde_json::from_slice(&data).wrap_err("error parsing Subsonic ID map")?
+        } else {
+            IdMap::default()
+        };
+        Ok(Self {
+            ids_path,
+            ids: Mutex::new(ids),
+            saved_changes: rocket::tokio::sync::Mutex::new(0),
+        })
+    }
+
+    /// Get the server connection.
+    fn server() -> &'static Server {
+        SERVER
+            .get()
+            .expect("Subsonic server used before initialisation")
+    }
+
+    /// Get our ID for a Subsonic identifier, remembering the mapping.
+    fn id(&self, kind: &str, key: &str) -> Result<Id> {
+        self.remember(stable_id(kind, key), key, |existing| existing == key)
+    }
+
+    /// Get our ID for a genre name, remembering the mapping.
+    ///
+    /// Genre names are matched case-insensitively, like by the local provider, so that
+    /// "Rock" and "rock" are the same genre. The first spelling seen is kept.
+    fn genre_id(&self, name: &str) -> Result<Id> {
+        let lower = name.to_lowercase();
+        self.remember(stable_id("genre", &lower), name, |existing| {
+            existing.to_lowercase() == lower
+        })
+    }
+
+    /// Map one of our IDs to a Subsonic identifier, unless it is already mapped to one
+    /// which `same` considers equivalent.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the ID is already mapped to a different identifier, rather
+    /// than mixing up the two objects.
+    fn remember(&self, id: Id, key: &str, same: impl Fn(&str) -> bool) -> Result<Id> {
+        let mut ids = self.ids.lock().expect("ID map lock poisoned");
+        match ids.ids.entry(id) {
+            Entry::Vacant(entry) => {
+                entry.insert(key.into());
+                ids.changes += 1;
+            }
+            Entry::Occupied(entry) if !same(entry.get()) => {
+                return Err(eyre!(
+                    "Subsonic identifiers {:?} and {key:?} both map to ID {id}",
+                    entry.get()
+                ));
+            }
+            Entry::Occupied(_) => {}
+        }
+        drop(ids);
+        Ok(id)
+    }
+
+    /// Get the Subsonic identifier for one of our IDs, if we have seen it before.
+    fn key(&self, id: Id) -> Option<String> {
+        self.ids
+            .lock()
+            .expect("ID map lock poisoned")
+            .ids
+            .get(&id)
+            .cloned()
+    }
+
+    /// Persist the ID mapping if it has changed.
+    ///
+    /// The map is written to a temporary file which then replaces the old one, so that
+    /// a crash while saving can't leave a truncated map.
+    async fn save_ids(&self) -> Result<()> {
+        let mut saved_changes = self.saved_changes.lock().await;
+        let (changes, data) = {
+            let ids = self.ids.lock().expect("ID map lock poisoned");
+            if ids.changes == *saved_changes {
+                return Ok(());
+            }
+            let data = serde_json::to_vec(&*ids).wrap_err("error serialising Subsonic ID map")?;
+            (ids.changes, data)
+        };
+        let temp_path = self.ids_path.with_extension("json.tmp");
+        rocket::tokio::fs::write(&temp_path, data)
+            .await
+            .wrap_err("error saving Subsonic ID map")?;
+        rocket::tokio::fs::rename(&temp_path, &self.ids_path)
+            .await
+            .wrap_err("error replacing Subsonic ID map")?;
+        *saved_changes = changes;
+        drop(saved_changes);
+        Ok(())
+    }
+
+    /// Convert songs to tracks, and save any new IDs.
+    async fn tracks(&self, songs: Vec<Song>) -> Result<Vec<Track>> {
+        let tracks = songs
+            .into_iter()
+            .map(|song| self.track_from(song))
+            .collect::<Result<_>>();
+        self.save_ids().await?;
+        tracks
+    }
+
+    /// Convert a Subsonic song to a track.
+    ///
+    /// Songs without an album ID (which some servers leave out for files with no album
+    /// tag) are given an album keyed by artist and title, see [`loose_album_title`].
+    fn track_from(&self, song: Song) -> Result<Track> {
+        let artist_name = song.artist.unwrap_or_else(|| "Unknown artist".into());
+        let artist_key = song.artist_id.unwrap_or_else(|| artist_name.clone());
+        let album_title = song.album.unwrap_or_else(|| "Unknown album".into());
+        let album_key = song
+            .album_id
+            .unwrap_or_else(|| format!("{artist_key}{LOOSE_ALBUM_SEPARATOR}{album_title}"));
+        let cover = cover_url(song.cover_art.as_deref());
+        Ok(Track {
+            id: self.id("track", &song.id)?,
+            title: song.title,
+            rank: i32::try_from(song.play_count.unwrap_or(0))
+                .unwrap_or(i32::MAX)
+                .saturating_add(1),
+            link: String::new(),
+            preview: song.id,
+            artist: Artist {
+                id: self.id("artist", &artist_key)?,
+                name: artist_name,
+                link: String::new(),
+                picture: String::new(),
+            },
+            album: PartialAlbum {
+                id: self.id("album", &album_key)?,
+                title: album_title,
+                cover,
+            },
+            duration: song
+                .duration
+                .map(|duration| i32::try_from(duration).unwrap_or(i32::MAX)),
+            explicit_lyrics: match song.explicit_status.as_deref() {
+                Some("explicit") => Some(true),
+                Some("clean") => Some(false),
+                _ => None,
+            },
+            #[allow(clippy::cast_precision_loss)]
+            bpm: song.bpm.filter(|bpm| *bpm > 0).map(|bpm| bpm as f32),
+            isrc: song.isrc.into_iter().next(),
+            readable: true,
+            available_countries: None,
+        })
+    }
+
+    /// Fetch a playlist with `getPlaylist`.
+    ///
+    /// Players can't discover our IDs for playlists, so an ID we haven't seen before is
+    /// used as the Subsonic ID directly, which works for servers with numeric IDs.
+    async fn get_playlist(&self, playlist_id: Id) -> Result<Option<PlaylistWrap>> {
+        let key = self
+            .key(playlist_id)
+            .unwrap_or_else(|| playlist_id.to_string());
+        Self::server()
+            .try_call("getPlaylist", &[("id", &key)])
+            .await
+            .wrap_err("error fetching playlist")
+    }
+
+    /// Get a genre object for a genre name.
+    fn genre_from(&self, name: String) -> Result<Genre> {
+        Ok(Genre {
+            id: self.genre_id(&name)?,
+            name,
+            picture: String::new(),
+        })
+    }
+}
+
+#[rocket::async_trait]
+impl MusicProvider for Subsonic {
+    /// There is no popularity data in the Subsonic API, so a "chart" is a random
+    /// selection of tracks.
+    async fn chart(&self, genre_id: Id) -> Result<Vec<Track>> {
+        let size = CHART_SIZE.to_string();
+        let mut query = vec![("size", size.as_str())];
+        let genre = if *genre_id == 0 {
+            None
+        } else {
+            Some(
+                self.key(genre_id)
+                    .ok_or_else(|| eyre!("unknown Subsonic genre: {genre_id}"))?,
+            )
+        };
+        if let Some(genre) = &genre {
+            query.push(("genre", genre));
+        }
+        let data: RandomSongs = Self::server()
+            .call("getRandomSongs", &query)
+            .await
+            .wrap_err("error fetching random songs")?;
+        self.tracks(data.random_songs.song).await
+    }
+
+    /// Like [`Self::chart`], a random selection of tracks from the range.
+    async fn era_chart(&self, start_year: i32, end_year: i32) -> Result<Vec<Track>> {
+        let size = CHART_SIZE.to_string();
+        let from_year = start_year.to_string();
+        let to_year = end_year.to_string();
+        let data: RandomSongs = Self::server()
+            .call(
+                "getRandomSongs",
+                &[
+                    ("size", size.as_str()),
+                    ("fromYear", &from_year),
+                    ("toYear", &to_year),
+                ],
+            )
+            .await
+            .wrap_err("error fetching random songs from era")?;
+        self.tracks(data.random_songs.song).await
+    }
+
+    async fn playlist(&self, playlist_id: Id) -> Result<Option<Playlist>> {
+        let Some(data) = self.get_playlist(playlist_id).await? else {
+            return Ok(None);
+        };
+        Ok(Some(Playlist {
+            id: playlist_id,
+            title: data.playlist.name,
+            link: String::new(),
+            picture: cover_url(data.playlist.cover_art.as_deref()),
+        }))
+    }
+
+    async fn playlist_tracks(&self, playlist_id: Id) -> Result<Vec<Track>> {
+        let Some(data) = self.get_playlist(playlist_id).await? else {
+            return Ok(Vec::new());
+        };
+        self.tracks(data.playlist.entry).await
+    }
+
+    /// Artists without a Subsonic ID are keyed by their name, so they exist as long as
+    /// we have seen them.
+    async fn artist(&self, artist_id: Id) -> Result<Option<Artist>> {
+        let Some(key) = self.key(artist_id) else {
+            return Ok(None);
+        };
+        let artist: Option<ArtistWrap> = Self::server()
+            .try_call("getArtist", &[("id", &key)])
+            .await
+            .wrap_err("error fetching artist")?;
+        Ok(Some(Artist {
+            id: artist_id,
+            name: artist.map_or(key, |artist| artist.artist.name),
+            link: String::new(),
+            picture: String::new(),
+        }))
+    }
+
+    /// `getTopSongs` takes an artist name, which for artists without a Subsonic ID is
+    /// the key we already have.
+    async fn artist_top(&self, artist_id: Id) -> Result<Vec<Track>> {
+        let Some(key) = self.key(artist_id) else {
+            return Ok(Vec::new());
+        };
+        let artist: Option<ArtistWrap> = Self::server()
+            .try_call("getArtist", &[("id", &key)])
+            .await
+            .wrap_err("error fetching artist")?;
+        let name = artist.map_or(key, |artist| artist.artist.name);
+        let size = ARTIST_TOP_SIZE.to_string();
+        let data: TopSongs = Self::server()
+            .call("getTopSongs", &[("artist", &name), ("count", &size)])
+            .await
+            .wrap_err("error fetching artist top songs")?;
+        self.tracks(data.top_songs.song).await
+    }
+
+    async fn related_artists(&self, artist_id: Id) -> Result<Vec<Artist>> {
+        let Some(key) = self.key(artist_id) else {
+            return Ok(Vec::new());
+        };
+        let size = RELATED_ARTISTS_SIZE.to_string();
+        let data: Option<ArtistInfoWrap> = Self::server()
+            .try_call("getArtistInfo2", &[("id", &key), ("count", &size)])
+            .await
+            .wrap_err("error fetching related artists")?;
+        let artists = data
+            .map(|data| data.artist_info2.similar_artist)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|artist| {
+                Ok(Artist {
+                    id: self.id("artist", &artist.id)?,
+                    name: artist.name,
+                    link: String::new(),
+                    picture: String::new(),
+                })
+            })
+            .collect::<Result<_>>();
+        self.save_ids().await?;
+        artists
+    }
+
+    async fn genres(&self) -> Result<Vec<Genre>> {
+        let data: Genres = Self::server()
+            .call("getGenres", &[])
+            .await
+            .wrap_err("error fetching genre list")?;
+        let genres = data
+            .genres
+            .genre
+            .into_iter()
+            .filter(|genre| genre.song_count > 0)
+            .map(|genre| self.genre_from(genre.value))
+            .collect::<Result<_>>();
+        self.save_ids().await?;
+        genres
+    }
+
+    /// Albums of songs without an album ID can't be fetched, so all we know about them
+    /// is their title.
+    async fn album(&self, album_id: Id) -> Result<Album> {
+        let key = self
+            .key(album_id)
+            .ok_or_else(|| eyre!("unknown Subsonic album: {album_id}"))?;
+        if let Some(title) = loose_album_title(&key) {
+            return Ok(Album {
+                id: album_id,
+                title: title.into(),
+                cover: String::new(),
+                link: String::new(),
+                genres: Vec::new().into(),
+                release_date: None,
+                record_type: None,
+            });
+        }
+        let data: AlbumWrap = Self::server()
+            .call("getAlbum", &[("id", &key)])
+            .await
+            .wrap_err("error fetching album")?;
+        let album = data.album;
+        let mut names: Vec<_> = album.genres.into_iter().map(|g| g.name).collect();
+        if names.is_empty() {
+            names.extend(album.genre);
+        }
+        let genres: Result<Vec<_>> = names
+            .into_iter()
+            .map(|name| self.genre_from(name))
+            .collect();
+        self.save_ids().await?;
+        let mut genres = genres?;
+        // Names which only differ in case are the same genre.
+        let mut seen = HashSet::new();
+        genres.retain(|genre| seen.insert(genre.id));
+        Ok(Album {
+            id: album_id,
+            title: album.name,
+            cover: cover_url(album.cover_art.as_deref()),
+            link: String::new(),
+            genres: genres.into(),
+            // only the year is available, so use the first day of it
+            release_date: album
+                .year
+                .and_then(|year| NaiveDate::from_ymd_opt(year, 1, 1)),
+            record_type: album
+                .release_types
+                .into_iter()
+                .next()
+                .map(|kind| kind.to_lowercase()),
+        })
+    }
+
+    async fn track(&self, id: Id) -> Result<Option<Track>> {
+        let Some(key) = self.key(id) else {
+            return Ok(None);
+        };
+        let data: Option<SongWrap> = Self::server()
+            .try_call("getSong", &[("id", &key)])
+            .await
+            .wrap_err("error fetching track")?;
+        let track = data.map(|data| self.track_from(data.song)).transpose();
+        self.save_ids().await?;
+        track
+    }
+
+    async fn track_search(&self, q: &str) -> Result<Vec<Track>> {
+        let size = SEARCH_SIZE.to_string();
+        let data: Search = Self::server()
+            .call(
+                "search3",
+                &[
+                    ("query", q),
+                    ("songCount", &size),
+                    ("artistCount", "0"),
+                    ("albumCount", "0"),
+                ],
+            )
+            .await
+            .wrap_err("error searching tracks")?;
+        self.tracks(data.search_result3.song).await
+    }
+
+    /// The `preview` field of a Subsonic track is its song ID.
+    async fn track_preview(&self, preview_url: &str) -> Result<PreviewStream> {
+        let response = Self::server()
+            .request("stream")
+            .query(&[("id", preview_url), ("format", "mp3")])
+            .send()
+            .await
+            .wrap_err("error streaming a track from Subsonic")?
+            .error_for_status()
+            .wrap_err("Subsonic server returned an HTTP error")?;
+        Ok(response
+            .bytes_stream()
+            .map_err(|e| eyre::Report::new(e).wrap_err("error streaming track chunk"))
+            .boxed())
+    }
+}
+
+/// Separates the artist and title in the key of an album for songs without an album
+/// ID. Subsonic IDs never contain it.
+const LOOSE_ALBUM_SEPARATOR: char = '\0';
+
+/// Get the title of an album for songs without an album ID from its key, or `None` if
+/// the key is a Subsonic album ID.
+fn loose_album_title(key: &str) -> Option<&str> {
+    key.split_once(LOOSE_ALBUM_SEPARATOR)
+        .map(|(_, title)| title)
+}
+
+/// Encode bytes as lowercase hexadecimal.
+fn hex(bytes: &[u8]) -> String {
+    use std::fmt::Write;
+    bytes.iter().fold(String::new(), |mut out, b| {
+        write!(out, "{b:02x}").expect("writing to a string should not fail");
+        out
+    })
+}
+
+/// Get the URL we serve a cover art image at.
+fn cover_url(cover_art: Option<&str>) -> String {
+    cover_art.map_or_else(String::new, |id| format!("/api/subsonic/covers/{id}"))
+}
+
+/// Collect API routes for serving Subsonic data.
+pub fn routes() -> Vec<rocket::Route> {
+    routes![cover]
+}
+
+/// Proxy cover art from the Subsonic server, so as not to expose credentials to clients.
+#[get("/subsonic/covers/<id>")]
+async fn cover(
+    id: &str,
+    _throttle: Throttle<throttle::Covers>,
+) -> Result<(ContentType, Vec<u8>), Status> {
+    let server = SERVER.get().ok_or(Status::NotFound)?;
+    let response = server
+        .request("getCoverArt")
+        .query(&[("id", id), ("size", "1000")])
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(|_| Status::BadGateway)?;
+    let content_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(ContentType::parse_flexible)
+        .unwrap_or(ContentType::JPEG);
+    let bytes = response.bytes().await.map_err(|_| Status::BadGateway)?;
+    Ok((content_type, bytes.to_vec()))
+}
+
+/// The envelope every Subsonic response is wrapped in.
+#[derive(Deserialize)]
+struct Envelope<T> {
+    /// The response body.
+    #[serde(rename = "subsonic-response")]
+    response: T,
+}
+
+/// The status part of a response body.
+#[derive(Deserialize)]
+struct ResponseStatus {
+    /// The error, if the request failed.
+    error: Option<ErrorBody>,
+}
+
+/// An error returned by the server.
+#[derive(Deserialize)]
+struct ErrorBody {
+    /// The error code.
+    code: u32,
+    /// The error message.
+    #[serde(default)]
+    message: String,
+}
+
+/// A song (track) object.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Song {
+    /// Subsonic ID.
+    id: String,
+    /// Track title.
+    title: String,
+    /// Album title.
+    album: Option<String>,
+    /// Album ID.
+    album_id: Option<String>,
+    /// Artist name.
+    artist: Option<String>,
+    /// Artist ID.
+    artist_id: Option<String>,
+    /// Cover art ID.
+    cover_art: Option<String>,
+    /// How many times the track has been played on the server.
+    play_count: Option<u64>,
+    /// Track length in seconds.
+    duration: Option<u32>,
+    /// Beats per minute (`OpenSubsonic` extension).
+    bpm: Option<u32>,
+    /// International Standard Recording Codes (`OpenSubsonic` extension).
+    #[serde(default)]
+    isrc: Vec<String>,
+    /// `explicit`, `clean` or empty if unknown (`OpenSubsonic` extension).
+    explicit_status: Option<String>,
+}
+
+/// A list of songs, which the server may leave out entirely if empty.
+#[derive(Default, Deserialize)]
+struct SongList {
+    /// The songs.
+    #[serde(default)]
+    song: Vec<Song>,
+}
+
+/// Response body for `getRandomSongs`.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RandomSongs {
+    /// The songs.
+    #[serde(default)]
+    random_songs: SongList,
+}
+
+/// Response body for `search3`.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Search {
+    /// The search results.
+    #[serde(default)]
+    search_result3: SongList,
+}
+
+/// Response body for `getSong`.
+#[derive(Deserialize)]
+struct SongWrap {
+    /// The song.
+    song: Song,
+}
+
+/// Response body for `getGenres`.
+#[derive(Deserialize)]
+struct Genres {
+    /// The genres.
+    genres: GenreList,
+}
+
+/// A list of genres.
+#[derive(Deserialize)]
+struct GenreList {
+    /// The genres.
+    #[serde(default)]
+    genre: Vec<GenreEntry>,
+}
+
+/// A genre, as returned by `getGenres`.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GenreEntry {
+    /// The genre name.
+    value: String,
+    /// How many songs are in the genre.
+    #[serde(default)]
+    song_count: u32,
+}
+
+/// Response body for `getPlaylist`.
+#[derive(Deserialize)]
+struct PlaylistWrap {
+    /// The playlist.
+    playlist: PlaylistEntry,
+}
+
+/// A playlist, as returned by `getPlaylist`.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PlaylistEntry {
+    /// Playlist name.
+    name: String,
+    /// Cover art ID.
+    cover_art: Option<String>,
+    /// The songs in the playlist.
+    #[serde(default)]
+    entry: Vec<Song>,
+}
+
+/// Response body for `getTopSongs`.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TopSongs {
+    /// The songs.
+    #[serde(default)]
+    top_songs: SongList,
+}
+
+/// Response body for `getArtist`.
+#[derive(Deserialize)]
+struct ArtistWrap {
+    /// The artist.
+    artist: ArtistEntry,
+}
+
+/// An artist, as returned by `getArtist` or as a similar artist.
+#[derive(Deserialize)]
+struct ArtistEntry {
+    /// Subsonic ID.
+    id: String,
+    /// Artist name.
+    name: String,
+}
+
+/// Response body for `getArtistInfo2`.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ArtistInfoWrap {
+    /// The artist info.
+    artist_info2: ArtistInfo,
+}
+
+/// Extra information about an artist.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ArtistInfo {
+    /// Similar artists.
+    #[serde(default)]
+    similar_artist: Vec<ArtistEntry>,
+}
+
+/// Response body for `getAlbum`.
+#[derive(Deserialize)]
+struct AlbumWrap {
+    /// The album.
+    album: AlbumEntry,
+}
+
+/// An album, as returned by `getAlbum`.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AlbumEntry {
+    /// Album title.
+    name: String,
+    /// Cover art ID.
+    cover_art: Option<String>,
+    /// The album's genre (classic Subsonic).
+    genre: Option<String>,
+    /// The album's genres (`OpenSubsonic` extension).
+    #[serde(default)]
+    genres: Vec<GenreName>,
+    /// The year the album was released.
+    year: Option<i32>,
+    /// Release types such as `Album` or `Single` (`OpenSubsonic` extension).
+    #[serde(default)]
+    release_types: Vec<String>,
+}
+
+/// A named genre reference (`OpenSubsonic` extension).
+#[derive(Deserialize)]
+struct GenreName {
+    /// The genre name.
+    name: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::local::asynchronous::Client;
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+    };
+
+    /// The `search3` response: one song on a known album, and one without an album ID.
+    const SEARCH: &str = r#"{"subsonic-response":{"status":"ok","searchResult3":{"song":[
+        {"id":"s1","title":"First","album":"Known","albumId":"al1","artist":"Band",
+            "artistId":"ar1","coverArt":"al1","playCount":4,"duration":200,
+            "explicitStatus":"explicit"},
+        {"id":"s2","title":"Second","album":"Loose","artist":"Band","artistId":"ar1"}
+    ]}}}"#;
+
+    /// The `getAlbum` response for the known album.
+    const ALBUM: &str = r#"{"subsonic-response":{"status":"ok","album":{"name":"Known",
+        "coverArt":"al1","genre":"Rock","genres":[{"name":"Rock"},{"name":"rock"}],
+        "year":1999,"releaseTypes":["Album"]}}}"#;
+
+    /// The response to anything else.
+    const NOT_FOUND: &str = r#"{"subsonic-response":{"status":"failed",
+        "error":{"code":70,"message":"not found"}}}"#;
+
+    /// The cover art image.
+    const COVER: &[u8] = b"\x89PNG fake image";
+
+    /// Run a stand-in Subsonic server on a local port, returning its URL.
+    fn serve() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("should bind a local port");
+        let url = format!(
+            "http://{}",
+            listener.local_addr().expect("should have an address")
+        );
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().map_while(Result::ok) {
+                let mut reader = BufReader::new(&stream);
+                let mut request_line = String::new();
+                let _ = reader.read_line(&mut request_line);
+                let mut line = String::new();
+                while reader.read_line(&mut line).is_ok_and(|n| n > 2) {
+                    line.clear();
+                }
+                let target = request_line.split(' ').nth(1).unwrap_or_default();
+                let (path, query) = target.split_once('?').unwrap_or((target, ""));
+                let (content_type, body) = match path {
+                    "/rest/search3" => ("application/json", SEARCH.as_bytes()),
+                    "/rest/getAlbum" if query.split('&').any(|param| param == "id=al1") => {
+                        ("application/json", ALBUM.as_bytes())
+                    }
+                    "/rest/getCoverArt" => ("image/png", COVER),
+                    _ => ("application/json", NOT_FOUND.as_bytes()),
+                };
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Type: {content_type}\r\n\
+                    Content-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                let _ = stream.write_all(body);
+            }
+        });
+        url
+    }
+
+    /// Get the provider, connected to a stand-in server. There can only be one, since
+    /// the server connection is global.
+    fn subsonic() -> &'static Subsonic {
+        static PROVIDER: OnceLock<Subsonic> = OnceLock::new();
+        PROVIDER.get_or_init(|| {
+            let media_dir =
+                std::env::temp_dir().join(format!("beatdrop-subsonic-{}", std::process::id()));
+            std::fs::create_dir_all(&media_dir).expect("should create a media directory");
+            let config = Config {
+                url: serve(),
+                username: "user".into(),
+                password: "pass".into(),
+                timeout: default_timeout(),
+            };
+            Subsonic::new(&config, &media_dir).expect("provider should set up")
+        })
+    }
+
+    #[rocket::async_test]
+    async fn search_maps_songs_to_tracks() {
+        let tracks = subsonic()
+            .track_search("band")
+            .await
+            .expect("search should succeed");
+        let [first, second] = &tracks[..] else {
+            panic!("expected two tracks, got {}", tracks.len());
+        };
+        assert_eq!(first.id, stable_id("track", "s1"));
+        assert_eq!(first.title, "First");
+        assert_eq!(first.rank, 5, "rank should be one more than the play count");
+        assert_eq!(first.preview, "s1");
+        assert_eq!(first.duration, Some(200));
+        assert_eq!(first.explicit_lyrics, Some(true));
+        assert_eq!(
+            second.duration, None,
+            "unknown durations should not be zero"
+        );
+        assert_eq!(second.explicit_lyrics, None);
+        assert_eq!(first.artist.id, second.artist.id);
+        assert_eq!(first.album.id, stable_id("album", "al1"));
+        assert_eq!(first.album.cover, "/api/subsonic/covers/al1");
+        assert_eq!(
+            second.album.id,
+            stable_id("album", "ar1\0Loose"),
+            "a song without an album ID should be keyed by artist and album title"
+        );
+    }
+
+    #[rocket::async_test]
+    async fn albums_are_fetched_or_made_up() {
+        let subsonic = subsonic();
+        let tracks = subsonic
+            .track_search("band")
+            .await
+            .expect("search should succeed");
+        let known = subsonic
+            .album(tracks[0].album.id)
+            .await
+            .expect("known album should be fetched");
+        assert_eq!(known.title, "Known");
+        assert_eq!(known.release_date, NaiveDate::from_ymd_opt(1999, 1, 1));
+        assert_eq!(known.record_type.as_deref(), Some("album"));
+        let genres: Vec<_> = known
+            .genres
+            .iter()
+            .map(|genre| genre.name.as_str())
+            .collect();
+        assert_eq!(
+            genres,
+            ["Rock"],
+            "genres differing in case should be merged"
+        );
+        let loose = subsonic
+            .album(tracks[1].album.id)
+            .await
+            .expect("album without an ID should not be fetched");
+        assert_eq!(loose.title, "Loose");
+        assert!(loose.genres.is_empty());
+        assert_eq!(loose.release_date, None);
+    }
+
+    #[rocket::async_test]
+    async fn covers_are_proxied() {
+        subsonic();
+        throttle::set_up_for_tests();
+        let client = Client::untracked(rocket::build().mount("/api", routes()))
+            .await
+            .expect("rocket should be valid");
+        let response = client.get("/api/subsonic/covers/al1").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::PNG));
+        assert_eq!(response.into_bytes().await.as_deref(), Some(COVER));
+    }
+}
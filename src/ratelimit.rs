@@ -1,49 +1,156 @@
 //! Various ratelimiting utilities.
-use std::{sync::Arc, time::Duration};
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, Once,
+    },
+    time::{Duration, Instant},
+};
 
-use tokio::sync::Semaphore;
+use tokio::sync::{Notify, Semaphore};
+
+/// How urgently a request needs to be made.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    /// A user is waiting on the request.
+    #[default]
+    Interactive,
+    /// The request is part of bulk work which nobody is waiting on, such as a
+    /// catalog refresh. These requests yield to interactive ones.
+    Background,
+}
+
+tokio::task_local! {
+    /// The priority of requests made by the current task, if not the default.
+    static PRIORITY: Priority;
+}
+
+impl Priority {
+    /// Get the priority of requests made by the current task.
+    pub fn current() -> Self {
+        PRIORITY.try_with(|priority| *priority).unwrap_or_default()
+    }
+
+    /// Run a future, giving requests it makes (in the same task) this priority.
+    pub async fn scope<F: Future>(self, future: F) -> F::Output {
+        PRIORITY.scope(self, future).await
+    }
+}
 
 /// A simple "leaky bucket" rate limiter. This is intended to be used as a
-/// long-lived singleton, and will spawn a background task.
+/// long-lived singleton, and will spawn a background task when first used.
+///
+/// Requests are made with a [`Priority`]: background requests never use the last
+/// `reserved` permits, and wait while any interactive requests are waiting, so that
+/// bulk work can't starve users.
 ///
 /// Based on [the example from the docs][1].
 ///
 /// [1]: https://docs.rs/tokio/1.36.0/tokio/sync/struct.Semaphore.html#rate-limiting-using-a-token-bucket
-pub struct Ratelimit(Arc<Semaphore>);
+pub struct Ratelimit {
+    /// The semaphore holding available permits.
+    sem: Arc<Semaphore>,
+    /// Notified whenever permits are added back to the semaphore.
+    refilled: Arc<Notify>,
+    /// The number of interactive requests waiting for a permit.
+    interactive_waiting: AtomicUsize,
+    /// The maximum number of requests to allow at once.
+    max_requests: usize,
+    /// The number of permits only interactive requests may use.
+    reserved: usize,
+    /// How long to wait before allowing another request.
+    increment_interval: Duration,
+    /// Ensures the background task is only started once.
+    started: Once,
+}
 
 impl Ratelimit {
-    /// Set up the ratelimiter and start the background task.
+    /// Set up the ratelimiter. The background task is started lazily, so this
+    /// may be called outside of an async runtime.
     ///
     /// `max_requests` is the maximum number of requests to allow at once.
+    /// `reserved` is how many of those are reserved for interactive requests.
     /// `increment_interval` is how long to wait before allowing another request.
-    pub fn new(max_requests: usize, increment_interval: Duration) -> Self {
-        let sem = Arc::new(Semaphore::new(max_requests));
-        tokio::spawn({
-            let sem = sem.clone();
-            let mut interval = tokio::time::interval(increment_interval);
-            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
-
-            async move {
-                loop {
-                    interval.tick().await;
-                    if sem.available_permits() < max_requests {
-                        sem.add_permits(1);
-                    }
+    pub fn new(max_requests: usize, reserved: usize, increment_interval: Duration) -> Self {
+        Self {
+            sem: Arc::new(Semaphore::new(max_requests)),
+            refilled: Arc::new(Notify::new()),
+            interactive_waiting: AtomicUsize::new(0),
+            max_requests,
+            // Background requests must always be able to make progress eventually.
+            reserved: reserved.min(max_requests.saturating_sub(1)),
+            increment_interval,
+            started: Once::new(),
+        }
+    }
+
+    /// Start the background task which adds permits back to the semaphore.
+    fn start(&self) {
+        let sem = self.sem.clone();
+        let refilled = self.refilled.clone();
+        let max_requests = self.max_requests;
+        let mut interval = tokio::time::interval(self.increment_interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        tokio::spawn(async move {
+            loop {
+                interval.tick().await;
+                if sem.available_permits() < max_requests {
+                    sem.add_permits(1);
                 }
+                refilled.notify_waiters();
             }
         });
-        Self(sem)
     }
 
-    /// Acquire a permit to make a request. The future will resolve once a permit
-    /// is available.
+    /// Acquire a permit to make a request, with the priority of the current task
+    /// (see [`Priority::scope`]). The future will resolve once a permit is available.
     pub async fn wait(&self) {
-        self.0
+        self.started.call_once(|| self.start());
+        match Priority::current() {
+            Priority::Interactive => self.wait_interactive().await,
+            Priority::Background => self.wait_background().await,
+        }
+    }
+
+    /// Acquire a permit for an interactive request.
+    async fn wait_interactive(&self) {
+        /// Keeps count of waiting interactive requests, even if the wait is cancelled.
+        struct Waiting<'a>(&'a AtomicUsize);
+
+        impl Drop for Waiting<'_> {
+            fn drop(&mut self) {
+                self.0.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+
+        self.interactive_waiting.fetch_add(1, Ordering::SeqCst);
+        let _waiting = Waiting(&self.interactive_waiting);
+        self.sem
             .acquire()
             .await
             .expect("semaphore shouldn't be closed")
             .forget();
     }
+
+    /// Acquire a permit for a background request, once there is spare capacity.
+    async fn wait_background(&self) {
+        loop {
+            let refilled = self.refilled.notified();
+            tokio::pin!(refilled);
+            // Register for notifications before checking, so we can't miss a refill.
+            refilled.as_mut().enable();
+            if self.interactive_waiting.load(Ordering::SeqCst) == 0
+                && self.sem.available_permits() > self.reserved
+            {
+                if let Ok(permit) = self.sem.try_acquire() {
+                    permit.forget();
+                    return;
+                }
+            }
+            refilled.await;
+        }
+    }
 }
 
 /// An exponential backoff iterator.
@@ -76,3 +183,184 @@ impl Iterator for Backoff {
         Some(current)
     }
 }
+
+/// A circuit breaker, which stops requests to a failing service for a while so
+/// that callers fail fast instead of waiting on retries.
+///
+/// After `threshold` consecutive failures the circuit opens for `cooldown`. Once the
+/// cooldown has passed requests are let through again, but a single further failure
+/// reopens the circuit until a request succeeds.
+pub struct CircuitBreaker {
+    /// How many consecutive failures open the circuit.
+    threshold: u32,
+    /// How long the circuit stays open for.
+    cooldown: Duration,
+    /// The mutable state of the breaker.
+    state: Mutex<BreakerState>,
+}
+
+/// The mutable state of a [`CircuitBreaker`].
+struct BreakerState {
+    /// The number of consecutive failures.
+    failures: u32,
+    /// When the circuit closes again, if it is open.
+    open_until: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    /// Create a new, closed, circuit breaker.
+    pub const fn new(threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            threshold,
+            cooldown,
+            state: Mutex::new(BreakerState {
+                failures: 0,
+                open_until: None,
+            }),
+        }
+    }
+
+    /// Lock the breaker state. Poisoning is ignored since the state is always valid.
+    fn state(&self) -> std::sync::MutexGuard<'_, BreakerState> {
+        self.state
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    /// Check whether a request may be made, returning how long until the circuit
+    /// closes if it is open.
+    pub fn check(&self) -> Result<(), Duration> {
+        let open_until = self.state().open_until;
+        match open_until.and_then(|until| until.checked_duration_since(Instant::now())) {
+            Some(remaining) if !remaining.is_zero() => Err(remaining),
+            _ => Ok(()),
+        }
+    }
+
+    /// Record a successful request, closing the circuit.
+    pub fn success(&self) {
+        let mut state = self.state();
+        state.failures = 0;
+        state.open_until = None;
+    }
+
+    /// Record a failed request, returning true if this opened the circuit.
+    pub fn failure(&self) -> bool {
+        let mut state = self.state();
+        state.failures = state.failures.saturating_add(1);
+        if state.failures < self.threshold {
+            return false;
+        }
+        state.open_until = Some(Instant::now() + self.cooldown);
+        true
+    }
+}
+
+/// A rate limiter keeping a separate allowance for each key (such as a client IP).
+///
+/// Each key may make `capacity` requests at once, and regains one every
+/// `interval`. This uses the generic cell rate algorithm, so only one timestamp is
+/// stored per key, and keys which have regained their full allowance are forgotten.
+pub struct KeyedRatelimit<K> {
+    /// The maximum number of requests a key may make at once.
+    capacity: u32,
+    /// How long it takes a key to regain one request.
+    interval: Duration,
+    /// The state of each key.
+    keys: Mutex<KeyedState<K>>,
+}
+
+/// The mutable state of a [`KeyedRatelimit`].
+struct KeyedState<K> {
+    /// The time at which each key will have regained its full allowance.
+    full_at: std::collections::HashMap<K, Instant>,
+    /// How many keys to store before forgetting those with a full allowance.
+    cleanup_at: usize,
+}
+
+impl<K: std::hash::Hash + Eq> KeyedRatelimit<K> {
+    /// The minimum number of keys to store before cleaning up.
+    const MIN_CLEANUP_AT: usize = 1024;
+
+    /// Create a rate limiter allowing `capacity` requests per `period` for each key.
+    pub fn new(capacity: u32, period: Duration) -> Self {
+        let capacity = capacity.max(1);
+        Self {
+            capacity,
+            interval: period / capacity,
+            keys: Mutex::new(KeyedState {
+                full_at: std::collections::HashMap::new(),
+                cleanup_at: Self::MIN_CLEANUP_AT,
+            }),
+        }
+    }
+
+    /// Count a request for a key, returning how long until it would be allowed if
+    /// the key has no allowance left.
+    pub fn check(&self, key: K) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut state = self
+            .keys
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        if state.full_at.len() >= state.cleanup_at {
+            state.full_at.retain(|_, full_at| *full_at > now);
+            state.cleanup_at = Self::MIN_CLEANUP_AT.max(state.full_at.len() * 2);
+        }
+        let full_at = state.full_at.entry(key).or_insert(now);
+        let next = (*full_at).max(now) + self.interval;
+        let burst = self.interval * self.capacity;
+        if let Some(excess) = (next - now).checked_sub(burst).filter(|d| !d.is_zero()) {
+            return Err(excess);
+        }
+        *full_at = next;
+        drop(state);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keyed_ratelimit_refills() {
+        let limiter = KeyedRatelimit::new(2, Duration::from_millis(200));
+        assert!(limiter.check("a").is_ok());
+        assert!(limiter.check("a").is_ok());
+        let retry_after = limiter.check("a").expect_err("allowance should be used up");
+        assert!(retry_after <= Duration::from_millis(100));
+        assert!(
+            limiter.check("b").is_ok(),
+            "keys should be limited separately"
+        );
+        std::thread::sleep(retry_after);
+        assert!(limiter.check("a").is_ok(), "a request should be regained");
+        assert!(limiter.check("a").is_err());
+    }
+
+    #[test]
+    fn circuit_breaker_opens_and_recloses() {
+        let breaker = CircuitBreaker::new(2, Duration::from_millis(100));
+        assert!(!breaker.failure());
+        assert!(
+            breaker.check().is_ok(),
+            "one failure should not open the circuit"
+        );
+        assert!(breaker.failure());
+        let retry_after = breaker.check().expect_err("the circuit should be open");
+        assert!(retry_after <= Duration::from_millis(100));
+        std::thread::sleep(retry_after);
+        assert!(breaker.check().is_ok(), "the cooldown should have passed");
+        assert!(
+            breaker.failure(),
+            "one more failure should reopen the circuit"
+        );
+        breaker.success();
+        assert!(breaker.check().is_ok());
+        assert!(
+            !breaker.failure(),
+            "a success should reset the failure count"
+        );
+    }
+}
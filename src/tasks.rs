@@ -1,14 +1,20 @@
 //! Background tasks management.
-use crate::{database, game::Game, track};
+use crate::{database, game::Game, provider, ratelimit::Priority, track};
 use eyre::{Context, Result};
 use rocket_db_pools::Database;
-use std::{future::Future, sync::OnceLock};
+use std::{
+    future::Future,
+    sync::{Mutex, OnceLock, PoisonError},
+};
 
 use clokwerk::{AsyncScheduler, Job, TimeUnits};
 
 /// A reference to the global database pool, for use in background tasks.
 static DB_POOL: OnceLock<database::Main> = OnceLock::new();
 
+/// The names of scheduled background tasks which are currently running.
+static RUNNING: Mutex<Vec<&'static str>> = Mutex::new(Vec::new());
+
 /// Spawn a task to take care of running periodic background tasks.
 pub async fn spawn(rocket: rocket::Rocket<rocket::Build>) -> rocket::fairing::Result {
     let Some(db) = database::Main::fetch(&rocket) else {
@@ -23,21 +29,37 @@ pub async fn spawn(rocket: rocket::Rocket<rocket::Build>) -> rocket::fairing::Re
     scheduler
         .every(1.day())
         .at("00:00")
-        .run(|| run_background_task("ensure daily chosen at midnight", ensure_daily_chosen));
+        .run(|| spawn_background_task("ensure daily chosen at midnight", ensure_daily_chosen));
     scheduler
         .every(1.minute())
-        .run(|| run_background_task("end timed-out games", end_timed_out_games));
+        .run(|| spawn_background_task("end timed-out games", end_timed_out_games));
+    scheduler
+        .every(6.hours())
+        .run(|| spawn_background_task("refresh catalog", refresh_catalog));
+    scheduler
+        .every(1.hour())
+        .run(|| spawn_background_task("verify stale tracks", verify_stale_tracks));
+    scheduler
+        .every(10.minutes())
+        .run(|| spawn_background_task("recalculate pick weights", recalculate_pick_weights));
+    scheduler
+        .every(1.day())
+        .at("03:00")
+        .run(|| spawn_background_task("prune provider cache", prune_provider_cache));
     rocket::tokio::task::spawn(async move {
-        // Check for new tasks once a minute.
+        // Check for new tasks once a minute. Tasks are spawned separately, so a slow
+        // one (like a catalog refresh) doesn't delay the others.
         loop {
             scheduler.run_pending().await;
-            rocket::tokio::time::sleep(std::time::Duration::from_secs(60)).await;
+            rocket::tokio::time::sleep(std::time::Duration::from_mins(1)).await;
         }
     });
     Ok(rocket)
 }
 
 /// Run an async background task and catch any errors or panics.
+///
+/// Rate limited requests made by the task are given [`Priority::Background`].
 async fn run_background_task<O: Future<Output = Result<()>> + Send, F: FnMut() -> O + Send>(
     name: &'static str,
     task: F,
@@ -49,11 +71,49 @@ async fn run_background_task<O: Future<Output = Result<()>> + Send, F: FnMut() -
             return;
         }
     };
-    if let Err(e) = future.await {
+    if let Err(e) = Priority::Background.scope(future).await {
         eprintln!("error in background task '{name}': {e:?}");
     }
 }
 
+/// Spawn a scheduled background task to run separately from the scheduler, catching
+/// any errors or panics (see [`run_background_task`]).
+///
+/// If the task is still running from last time it was scheduled, it is skipped. This
+/// returns immediately, with a ready future so that it can be used as a scheduler job.
+fn spawn_background_task<O, F>(name: &'static str, task: F) -> std::future::Ready<()>
+where
+    O: Future<Output = Result<()>> + Send + 'static,
+    F: FnMut() -> O + Send + 'static,
+{
+    /// Marks the task as no longer running once dropped, even if it panics.
+    struct Running(&'static str);
+
+    impl Drop for Running {
+        fn drop(&mut self) {
+            RUNNING
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .retain(|name| *name != self.0);
+        }
+    }
+
+    {
+        let mut running = RUNNING.lock().unwrap_or_else(PoisonError::into_inner);
+        if running.contains(&name) {
+            eprintln!("skipping background task '{name}' which is still running");
+            return std::future::ready(());
+        }
+        running.push(name);
+    }
+    let running = Running(name);
+    rocket::tokio::task::spawn(async move {
+        let _running = running;
+        run_background_task(name, task).await;
+    });
+    std::future::ready(())
+}
+
 /// Get a database connection from the pool.
 async fn db_conn() -> Result<sqlx::pool::PoolConnection<sqlx::Postgres>> {
     DB_POOL
@@ -84,3 +144,46 @@ async fn end_timed_out_games() -> Result<()> {
         .wrap_err("error ending timed-out games as a background task")?;
     Ok(())
 }
+
+/// Refresh genre charts and prune tracks which no longer exist.
+///
+/// This keeps the catalog and ranks up to date, and finds ghost tracks in bulk rather
+/// than one at a time while picking tracks for players.
+async fn refresh_catalog() -> Result<()> {
+    track::refresh::catalog(&mut *db_conn().await?)
+        .await
+        .wrap_err("error refreshing the catalog as a background task")?;
+    Ok(())
+}
+
+/// Re-check a batch of tracks which haven't been verified recently.
+///
+/// Picking relies on tracks having been verified recently, so this runs more often
+/// than full catalog refreshes to keep up with large catalogs.
+async fn verify_stale_tracks() -> Result<()> {
+    track::refresh::verify_stale(&mut *db_conn().await?)
+        .await
+        .wrap_err("error verifying stale tracks as a background task")?;
+    Ok(())
+}
+
+/// Recalculate the weights used to pick tracks by rank.
+///
+/// Players' games add tracks for new genres and eras, which are only weighted from
+/// scratch when picking until this next runs.
+async fn recalculate_pick_weights() -> Result<()> {
+    track::refresh::pick_weights(&mut *db_conn().await?)
+        .await
+        .wrap_err("error recalculating pick weights as a background task")?;
+    Ok(())
+}
+
+/// Delete expired responses from the music provider's cache, so that it doesn't grow
+/// without bound.
+async fn prune_provider_cache() -> Result<()> {
+    provider::get()
+        .prune_cache()
+        .await
+        .wrap_err("error pruning the provider cache as a background task")?;
+    Ok(())
+}
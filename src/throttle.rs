@@ -0,0 +1,360 @@
+//! Per-client rate limiting for API routes.
+//!
+//! Routes opt in by taking a [`Throttle`] request guard for one of the route groups
+//! defined here. Requests are counted per client IP, except that groups which are only
+//! useful to authenticated users count requests carrying a valid session per account.
+//! Clients which exceed the limit get a 429 response with a `Retry-After` header.
+//!
+//! Rocket sends failed request guards to a catcher rather than using the guard's error
+//! as the response, so the guard records the delay in the request-local cache for the
+//! catcher from [`catchers`] to use.
+use std::{marker::PhantomData, net::IpAddr, sync::OnceLock, time::Duration};
+
+use duration_string::DurationString;
+use rocket::{
+    catch, catchers,
+    request::{self, FromRequest},
+    Request,
+};
+use serde::Deserialize;
+
+use crate::{ratelimit::KeyedRatelimit, ApiError, Session};
+
+/// The rate limiters for each route group, set up on startup.
+static LIMITERS: OnceLock<Limiters> = OnceLock::new();
+
+/// Config options for per-client rate limiting. All are optional.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Addresses of reverse proxies whose `X-Forwarded-For` header should be trusted.
+    trusted_proxies: Vec<IpAddr>,
+    /// The limit for creating accounts.
+    new_user: Limit,
+    /// The limit for logging in.
+    login: Limit,
+    /// The limit for searching for tracks.
+    search: Limit,
+    /// The limit for starting games and making guesses.
+    game: Limit,
+    /// The limit for fetching cover art proxied from the music provider.
+    covers: Limit,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            trusted_proxies: Vec::new(),
+            new_user: Limit::new(10, Duration::from_hours(1)),
+            login: Limit::new(20, Duration::from_mins(1)),
+            search: Limit::new(30, Duration::from_mins(1)),
+            game: Limit::new(60, Duration::from_mins(1)),
+            covers: Limit::new(120, Duration::from_mins(1)),
+        }
+    }
+}
+
+/// How many requests a client may make in a route group.
+#[derive(Debug, Deserialize)]
+pub struct Limit {
+    /// The number of requests allowed per `per`, or 0 for no limit.
+    requests: u32,
+    /// The period over which requests are counted.
+    per: DurationString,
+}
+
+impl Limit {
+    /// Create a limit of `requests` per `per`.
+    fn new(requests: u32, per: Duration) -> Self {
+        Self {
+            requests,
+            per: per.into(),
+        }
+    }
+
+    /// Build the rate limiter for this limit, if it is enabled.
+    fn limiter(&self) -> Option<KeyedRatelimit<Client>> {
+        (self.requests > 0).then(|| KeyedRatelimit::new(self.requests, self.per.into()))
+    }
+}
+
+/// The rate limiters for each route group.
+pub struct Limiters {
+    /// Addresses of trusted reverse proxies.
+    trusted_proxies: Vec<IpAddr>,
+    /// See [`NewUser`].
+    new_user: Option<KeyedRatelimit<Client>>,
+    /// See [`Login`].
+    login: Option<KeyedRatelimit<Client>>,
+    /// See [`Search`].
+    search: Option<KeyedRatelimit<Client>>,
+    /// See [`Game`].
+    game: Option<KeyedRatelimit<Client>>,
+    /// See [`Covers`].
+    covers: Option<KeyedRatelimit<Client>>,
+}
+
+/// Set up per-client rate limiting. This must be called exactly once, before
+/// handling any requests.
+pub fn init(config: &crate::Config) {
+    let config = &config.throttle;
+    let limiters = Limiters {
+        trusted_proxies: config.trusted_proxies.clone(),
+        new_user: config.new_user.limiter(),
+        login: config.login.limiter(),
+        search: config.search.limiter(),
+        game: config.game.limiter(),
+        covers: config.covers.limiter(),
+    };
+    LIMITERS
+        .set(limiters)
+        .map_err(|_| ())
+        .expect("throttle::init must only be called once");
+}
+
+/// Who a request is counted against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Client {
+    /// An authenticated account.
+    Account(i32),
+    /// An unauthenticated client, by IP address.
+    Ip(IpAddr),
+}
+
+impl Client {
+    /// Identify the client making a request, by account if `by_account` is set and
+    /// the request carries a valid session, and otherwise by IP.
+    fn of(req: &Request<'_>, trusted_proxies: &[IpAddr], by_account: bool) -> Self {
+        if by_account {
+            if let Some(session) = req
+                .headers()
+                .get_one("Authorization")
+                .and_then(|header| Session::from_auth_header(header).ok())
+            {
+                return Self::Account(session.account_id());
+            }
+        }
+        let Some(remote) = req.remote().map(|addr| addr.ip()) else {
+            return Self::Ip(IpAddr::from([0, 0, 0, 0]));
+        };
+        if !trusted_proxies.contains(&remote) {
+            return Self::Ip(remote);
+        }
+        // Each proxy appends the address it received the request from, so the
+        // rightmost address not belonging to one of our proxies is the client.
+        let forwarded: Vec<IpAddr> = req
+            .headers()
+            .get("X-Forwarded-For")
+            .flat_map(|header| header.split(','))
+            .filter_map(|addr| addr.trim().parse().ok())
+            .collect();
+        let client = forwarded
+            .iter()
+            .rev()
+            .find(|addr| !trusted_proxies.contains(addr))
+            .or_else(|| forwarded.first())
+            .copied()
+            .unwrap_or(remote);
+        Self::Ip(client)
+    }
+}
+
+/// A group of routes sharing a rate limit.
+pub trait Group: Send {
+    /// Whether authenticated requests are counted per account rather than per IP.
+    ///
+    /// This must be false for groups which give out accounts or sessions, or else each
+    /// new account would get a fresh allowance.
+    const BY_ACCOUNT: bool;
+
+    /// Get the limiter for this group, if it is enabled.
+    fn limiter(limiters: &Limiters) -> Option<&KeyedRatelimit<Client>>;
+}
+
+/// Creating accounts.
+pub struct NewUser;
+
+/// Logging in.
+pub struct Login;
+
+/// Searching for tracks.
+pub struct Search;
+
+/// Starting games and making guesses.
+pub struct Game;
+
+/// Fetching cover art proxied from the music provider.
+///
+/// Images are loaded without the session header, so these are always counted by IP.
+pub struct Covers;
+
+impl Group for NewUser {
+    const BY_ACCOUNT: bool = false;
+
+    fn limiter(limiters: &Limiters) -> Option<&KeyedRatelimit<Client>> {
+        limiters.new_user.as_ref()
+    }
+}
+
+impl Group for Login {
+    const BY_ACCOUNT: bool = false;
+
+    fn limiter(limiters: &Limiters) -> Option<&KeyedRatelimit<Client>> {
+        limiters.login.as_ref()
+    }
+}
+
+impl Group for Search {
+    const BY_ACCOUNT: bool = true;
+
+    fn limiter(limiters: &Limiters) -> Option<&KeyedRatelimit<Client>> {
+        limiters.search.as_ref()
+    }
+}
+
+impl Group for Game {
+    const BY_ACCOUNT: bool = true;
+
+    fn limiter(limiters: &Limiters) -> Option<&KeyedRatelimit<Client>> {
+        limiters.game.as_ref()
+    }
+}
+
+impl Group for Covers {
+    const BY_ACCOUNT: bool = false;
+
+    fn limiter(limiters: &Limiters) -> Option<&KeyedRatelimit<Client>> {
+        limiters.covers.as_ref()
+    }
+}
+
+/// A request guard which counts the request against the client's limit for the
+/// route group `G`, failing with 429 Too Many Requests if it has been exceeded.
+pub struct Throttle<G: Group>(PhantomData<G>);
+
+#[rocket::async_trait]
+impl<'r, G: Group> FromRequest<'r> for Throttle<G> {
+    type Error = ApiError;
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let limiters = LIMITERS.get().expect("throttle used before initialisation");
+        if let Some(limiter) = G::limiter(limiters) {
+            let client = Client::of(req, &limiters.trusted_proxies, G::BY_ACCOUNT);
+            if let Err(retry_after) = limiter.check(client) {
+                req.local_cache(|| RetryAfter(Some(retry_after)));
+                return ApiError::TooManyRequests(retry_after).into_outcome();
+            }
+        }
+        request::Outcome::Success(Self(PhantomData))
+    }
+}
+
+/// How long a throttled request should wait, stored in the request-local cache.
+struct RetryAfter(Option<Duration>);
+
+/// Collect catchers for throttled API requests.
+pub fn catchers() -> Vec<rocket::Catcher> {
+    catchers![too_many_requests]
+}
+
+/// Respond to a request which failed a [`Throttle`] guard.
+#[catch(429)]
+fn too_many_requests(req: &Request<'_>) -> ApiError {
+    let RetryAfter(retry_after) = req.local_cache(|| RetryAfter(None));
+    ApiError::TooManyRequests(retry_after.unwrap_or_default())
+}
+
+/// Set up limiters for tests, allowing one request per hour for new users and searches
+/// and not limiting other groups.
+#[cfg(test)]
+pub fn set_up_for_tests() {
+    LIMITERS.get_or_init(|| Limiters {
+        trusted_proxies: Vec::new(),
+        new_user: Limit::new(1, Duration::from_hours(1)).limiter(),
+        login: None,
+        search: Limit::new(1, Duration::from_hours(1)).limiter(),
+        game: None,
+        covers: None,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::{
+        get,
+        http::{Header, Status},
+        local::asynchronous::Client as TestClient,
+        routes,
+    };
+    use std::net::SocketAddr;
+
+    #[get("/new_user")]
+    const fn new_user(_throttle: Throttle<NewUser>) {}
+
+    #[get("/search")]
+    const fn search(_throttle: Throttle<Search>) {}
+
+    /// Build a client for a server with one throttled route per group being tested.
+    async fn client() -> TestClient {
+        set_up_for_tests();
+        let rocket = rocket::build()
+            .mount("/", routes![new_user, search])
+            .register("/", catchers());
+        TestClient::untracked(rocket)
+            .await
+            .expect("rocket should be valid")
+    }
+
+    /// Make a request from an IP address, logged in as an account if given.
+    async fn request(client: &TestClient, uri: &str, ip: [u8; 4], account: Option<i32>) -> Status {
+        let mut req = client.get(uri).remote(SocketAddr::from((ip, 1234)));
+        if let Some(account) = account {
+            let token = Session::test_token(account);
+            req.add_header(Header::new("Authorization", format!("Bearer {token}")));
+        }
+        req.dispatch().await.status()
+    }
+
+    #[rocket::async_test]
+    async fn throttled_requests_get_retry_after() {
+        let client = client().await;
+        let ip = SocketAddr::from(([10, 0, 0, 1], 1234));
+        let first = client.get("/new_user").remote(ip).dispatch().await;
+        assert_eq!(first.status(), Status::Ok);
+        let second = client.get("/new_user").remote(ip).dispatch().await;
+        assert_eq!(second.status(), Status::TooManyRequests);
+        let retry_after: u64 = second
+            .headers()
+            .get_one("Retry-After")
+            .expect("throttled response should have Retry-After")
+            .parse()
+            .expect("Retry-After should be a number of seconds");
+        assert!((3500..=3600).contains(&retry_after), "got {retry_after}");
+    }
+
+    #[rocket::async_test]
+    async fn new_accounts_share_their_ip_allowance() {
+        let client = client().await;
+        let ip = [10, 0, 0, 2];
+        assert_eq!(request(&client, "/new_user", ip, Some(1)).await, Status::Ok);
+        assert_eq!(
+            request(&client, "/new_user", ip, Some(2)).await,
+            Status::TooManyRequests,
+            "a session for a different account must not give a fresh allowance"
+        );
+    }
+
+    #[rocket::async_test]
+    async fn authenticated_searches_are_counted_per_account() {
+        let client = client().await;
+        let ip = [10, 0, 0, 3];
+        assert_eq!(request(&client, "/search", ip, Some(3)).await, Status::Ok);
+        assert_eq!(request(&client, "/search", ip, Some(4)).await, Status::Ok);
+        assert_eq!(
+            request(&client, "/search", ip, Some(3)).await,
+            Status::TooManyRequests
+        );
+        assert_eq!(request(&client, "/search", ip, None).await, Status::Ok);
+    }
+}
@@ -1,98 +1,282 @@
 //! Efficiently insert multiple tracks into the database.
-use super::insert;
+use super::{insert, Pool};
 use std::collections::HashSet;
 
-use crate::{deezer, DbConn};
-use eyre::{Context, Result};
+use crate::{deezer, provider, provider::Unavailable, DbConn};
+use eyre::{eyre, Context, Result};
+use futures::{Future, StreamExt};
+use sqlx::Connection;
+
+/// The maximum number of requests to the music provider to make at once while
+/// inserting tracks. Requests are still subject to the provider's rate limit.
+pub const MAX_CONCURRENT_FETCHES: usize = 8;
+
+/// Fetch something for each of a set of IDs from the music provider concurrently,
+/// returning the results paired with their IDs.
+///
+/// Failed fetches are logged and skipped, so that one failure doesn't lose the rest of
+/// a batch. `what` describes the fetched objects for the log. An error is returned
+/// instead if the provider is unavailable, or if every fetch failed.
+pub async fn fetch_each<T, F, Fut>(
+    ids: Vec<deezer::Id>,
+    what: &str,
+    fetch: F,
+) -> Result<Vec<(deezer::Id, T)>>
+where
+    T: Send,
+    F: Fn(deezer::Id) -> Fut + Send + Sync,
+    Fut: Future<Output = Result<T>> + Send,
+{
+    let results: Vec<_> = futures::stream::iter(ids)
+        .map(|id| {
+            let fetched = fetch(id);
+            async move { (id, fetched.await) }
+        })
+        .buffer_unordered(MAX_CONCURRENT_FETCHES)
+        .collect()
+        .await;
+    let mut fetched = Vec::with_capacity(results.len());
+    let mut last_error = None;
+    for (id, result) in results {
+        match result {
+            Ok(data) => fetched.push((id, data)),
+            Err(e) if Unavailable::caused(&e) => return Err(e),
+            Err(e) => {
+                eprintln!("skipping {what} {id} which failed to fetch: {e:?}");
+                last_error = Some(e);
+            }
+        }
+    }
+    match last_error {
+        Some(e) if fetched.is_empty() => Err(e.wrap_err(format!("every {what} failed to fetch"))),
+        _ => Ok(fetched),
+    }
+}
 
 /// A helper for efficiently inserting multiple tracks into the database.
+///
+/// Rows are queued in memory and written in one query per table by [`Self::flush`],
+/// which must be called once all tracks have been added.
 pub struct BulkInserter<'a> {
     /// The database connection.
     db: &'a mut DbConn,
-    /// The IDs of all genres that have already been inserted during this operation.
-    inserted_genre_ids: HashSet<deezer::Id>,
-    /// The IDs of all albums that have already been inserted during this operation.
-    inserted_album_ids: HashSet<deezer::Id>,
-    /// The IDs of all artists that have already been inserted during this operation.
-    inserted_artist_ids: HashSet<deezer::Id>,
-    /// The IDs of all tracks that have already been inserted during this operation.
-    inserted_track_ids: HashSet<deezer::Id>,
+    /// The pool to insert tracks into.
+    pool: Pool,
+    /// The IDs of all genres that have already been queued during this operation.
+    seen_genre_ids: HashSet<deezer::Id>,
+    /// The IDs of all albums that have already been queued during this operation,
+    /// or which are known to already be in the database.
+    seen_album_ids: HashSet<deezer::Id>,
+    /// The IDs of albums which failed to fetch during this operation, so aren't retried.
+    failed_album_ids: HashSet<deezer::Id>,
+    /// The IDs of all artists that have already been queued during this operation.
+    seen_artist_ids: HashSet<deezer::Id>,
+    /// The IDs of all tracks that have already been queued during this operation.
+    seen_track_ids: HashSet<deezer::Id>,
+    /// Genres waiting to be inserted.
+    genres: Vec<deezer::Genre>,
+    /// Albums waiting to be inserted.
+    albums: Vec<deezer::Album>,
+    /// Album-genre relationships waiting to be inserted, as album and genre IDs.
+    album_genres: Vec<(deezer::Id, deezer::Id)>,
+    /// Artists waiting to be inserted.
+    artists: Vec<deezer::Artist>,
+    /// Tracks waiting to be inserted.
+    tracks: Vec<deezer::Track>,
 }
 
 impl<'a> BulkInserter<'a> {
-    /// Create a new bulk inserter.
-    pub fn new(db: &'a mut DbConn) -> Self {
+    /// Create a new bulk inserter, which inserts tracks into the given pool.
+    pub fn new(db: &'a mut DbConn, pool: Pool) -> Self {
         Self {
             db,
-            inserted_genre_ids: HashSet::new(),
-            inserted_album_ids: HashSet::new(),
-            inserted_artist_ids: HashSet::new(),
-            inserted_track_ids: HashSet::new(),
+            pool,
+            seen_genre_ids: HashSet::new(),
+            seen_album_ids: HashSet::new(),
+            failed_album_ids: HashSet::new(),
+            seen_artist_ids: HashSet::new(),
+            seen_track_ids: HashSet::new(),
+            genres: Vec::new(),
+            albums: Vec::new(),
+            album_genres: Vec::new(),
+            artists: Vec::new(),
+            tracks: Vec::new(),
         }
     }
 
-    /// Insert a track into the database, or update it if it already exists.
+    /// Queue a track to be inserted, or updated if it already exists.
+    ///
+    /// Returns an error if the track's album isn't in the database and can't be fetched.
     pub async fn insert_track(&mut self, track: &deezer::Track) -> Result<()> {
-        if self.inserted_track_ids.contains(&track.id) {
+        if !self.seen_track_ids.insert(track.id) {
             return Ok(());
         }
         self.insert_album(&track.album).await?;
-        self.insert_artist(&track.artist).await?;
-        insert::track(&mut *self.db, track).await?;
-        self.inserted_track_ids.insert(track.id);
+        self.insert_artist(&track.artist);
+        self.tracks.push(track.clone());
         Ok(())
     }
 
-    /// Insert an album into the database.
+    /// Fetch and queue all the albums referenced by some tracks which are not already
+    /// in the database, fetching them concurrently. Returns the number of new albums.
+    ///
+    /// This is an optimisation for inserting many tracks, since otherwise each album
+    /// would be fetched one at a time by [`Self::insert_track`].
+    pub async fn prefetch_albums(&mut self, tracks: &[deezer::Track]) -> Result<usize> {
+        let mut album_ids: Vec<i64> = tracks
+            .iter()
+            .map(|track| track.album.id)
+            .filter(|id| !self.seen_album_ids.contains(id))
+            .map(i64::from)
+            .collect();
+        album_ids.sort_unstable();
+        album_ids.dedup();
+        let existing = sqlx::query_scalar!("SELECT id FROM album WHERE id = ANY($1)", &album_ids)
+            .fetch_all(&mut *self.db)
+            .await
+            .wrap_err("error querying which albums exist")?;
+        self.seen_album_ids
+            .extend(existing.into_iter().map(deezer::Id::from));
+        let missing: Vec<deezer::Id> = album_ids
+            .into_iter()
+            .map(deezer::Id::from)
+            .filter(|id| !self.seen_album_ids.contains(id))
+            .collect();
+        let albums = fetch_each(missing.clone(), "album", |id| provider::get().album(id)).await?;
+        let count = albums.len();
+        for (_, album) in albums {
+            self.queue_album(album);
+        }
+        // Tracks on albums which failed to fetch can't be inserted.
+        self.failed_album_ids.extend(
+            missing
+                .into_iter()
+                .filter(|id| !self.seen_album_ids.contains(id)),
+        );
+        Ok(count)
+    }
+
+    /// Write all queued rows to the database in a single transaction, using one
+    /// query per table.
+    pub async fn flush(self) -> Result<()> {
+        let mut tx = self
+            .db
+            .begin()
+            .await
+            .wrap_err("error starting transaction for bulk insert")?;
+        insert::genres(&mut tx, &self.genres).await?;
+        insert::albums(&mut tx, &self.albums).await?;
+        insert::album_genres(&mut tx, &self.album_genres).await?;
+        insert::artists(&mut tx, &self.artists).await?;
+        insert::tracks(&mut tx, &self.tracks, self.pool).await?;
+        tx.commit().await.wrap_err("error committing bulk insert")
+    }
+
+    /// Queue an album to be inserted, if it isn't already in the database.
     ///
     /// If the album is not already in the database, the full album will be
-    /// fetched from the Deezer API and inserted.
+    /// fetched from the music provider.
     ///
     /// If the album is already in the database, nothing will be done. This is
-    /// to minimise calls to the Deezer API.
+    /// to minimise calls to the music provider.
     async fn insert_album(&mut self, album: &deezer::PartialAlbum) -> Result<()> {
         if self.album_exists(album.id).await? {
             return Ok(());
         }
-        let album = deezer::album(album.id).await?;
-        insert::album(&mut *self.db, &album).await?;
-        for genre in &*album.genres {
-            self.insert_genre(genre).await?;
-            insert::album_genre(&mut *self.db, album.id, genre.id).await?;
+        if self.failed_album_ids.contains(&album.id) {
+            return Err(eyre!("album {} failed to fetch", album.id));
         }
-        self.inserted_album_ids.insert(album.id);
+        let album = provider::get().album(album.id).await?;
+        self.queue_album(album);
         Ok(())
     }
 
-    /// Check if an album exists in the database.
+    /// Queue a fetched album and its genres to be inserted.
+    ///
+    /// Genres listed more than once for the album are only queued once.
+    fn queue_album(&mut self, album: deezer::Album) {
+        let mut album_genre_ids = HashSet::new();
+        for genre in &*album.genres {
+            if !album_genre_ids.insert(genre.id) {
+                continue;
+            }
+            if self.seen_genre_ids.insert(genre.id) {
+                self.genres.push(genre.clone());
+            }
+            self.album_genres.push((album.id, genre.id));
+        }
+        self.seen_album_ids.insert(album.id);
+        self.albums.push(album);
+    }
+
+    /// Check if an album exists in the database or has been queued.
     async fn album_exists(&mut self, album_id: deezer::Id) -> Result<bool> {
-        if self.inserted_album_ids.contains(&album_id) {
+        if self.seen_album_ids.contains(&album_id) {
             return Ok(true);
         }
-        let exists = sqlx::query_scalar!("SELECT 1 FROM album WHERE id = $1", i32::from(album_id),)
+        let exists = sqlx::query_scalar!("SELECT 1 FROM album WHERE id = $1", i64::from(album_id),)
             .fetch_optional(&mut *self.db)
             .await
             .wrap_err("error querying if album exists")?;
+        if exists.is_some() {
+            self.seen_album_ids.insert(album_id);
+        }
         Ok(exists.is_some())
     }
 
-    /// Insert a genre object into the database, or update it if it already exists.
-    async fn insert_genre(&mut self, genre: &deezer::Genre) -> Result<()> {
-        if self.inserted_genre_ids.contains(&genre.id) {
-            return Ok(());
+    /// Queue an artist object to be inserted, or updated if it already exists.
+    fn insert_artist(&mut self, artist: &deezer::Artist) {
+        if self.seen_artist_ids.insert(artist.id) {
+            self.artists.push(artist.clone());
         }
-        insert::genre(&mut *self.db, genre).await?;
-        self.inserted_genre_ids.insert(genre.id);
-        Ok(())
     }
+}
 
-    /// Insert an artist object into the database, or update it if it already exists.
-    async fn insert_artist(&mut self, artist: &deezer::Artist) -> Result<()> {
-        if self.inserted_artist_ids.contains(&artist.id) {
-            return Ok(());
-        }
-        insert::artist(&mut *self.db, artist).await?;
-        self.inserted_artist_ids.insert(artist.id);
-        Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// Fetch IDs 1 to 4, failing for ID 2 with the given error.
+    async fn fetch_failing_one(error: fn() -> eyre::Report) -> Result<Vec<(deezer::Id, u64)>> {
+        let ids = (1..=4).map(deezer::Id).collect();
+        fetch_each(ids, "thing", |id| async move {
+            if *id == 2 {
+                Err(error())
+            } else {
+                Ok(*id)
+            }
+        })
+        .await
+    }
+
+    #[rocket::async_test]
+    async fn fetch_each_skips_failures() {
+        let mut fetched = fetch_failing_one(|| eyre!("not found"))
+            .await
+            .expect("other fetches succeeded");
+        fetched.sort_unstable_by_key(|(_, n)| *n);
+        assert_eq!(fetched, [1, 3, 4].map(|n| (deezer::Id(n), n)));
+    }
+
+    #[rocket::async_test]
+    async fn fetch_each_fails_if_provider_unavailable() {
+        let e = fetch_failing_one(|| {
+            eyre::Report::new(Unavailable {
+                retry_after: Duration::from_secs(30),
+            })
+            .wrap_err("error fetching thing")
+        })
+        .await
+        .expect_err("the provider being unavailable should not be skipped");
+        assert!(Unavailable::caused(&e));
+    }
+
+    #[rocket::async_test]
+    async fn fetch_each_fails_if_every_fetch_fails() {
+        let ids = vec![deezer::Id(1), deezer::Id(2)];
+        let result: Result<Vec<(_, ())>> =
+            fetch_each(ids, "thing", |_| async { Err(eyre!("not found")) }).await;
+        assert!(result.is_err());
     }
 }
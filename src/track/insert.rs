@@ -1,120 +1,277 @@
 //! Database queries for inserting and updating music data in the database.
+//!
+//! Each kind of object can be inserted in bulk, using a single `UNNEST`-based
+//! query for any number of rows. Objects must not be repeated within one call.
 use crate::{
-    deezer::{self, Album, Artist, Genre, Track},
-    DbConn,
+    deezer::{self, Album, Artist, Genre, Playlist, Track},
+    provider, DbConn,
 };
+use chrono::NaiveDate;
 use eyre::{Context, Result};
 
+use super::Pool;
+
 /// Insert a track into the database, or update it if it already exists.
 ///
 /// Also inserts or updates objects the track references.
-pub async fn track_with_refs(db: &mut DbConn, track_data: &Track) -> Result<()> {
-    let album_data = deezer::album(track_data.album.id).await?;
-    album(db, &album_data).await?;
-    for genre_data in &*album_data.genres {
-        genre(db, genre_data).await?;
-        album_genre(db, album_data.id, genre_data.id).await?;
-    }
-    artist(db, &track_data.artist).await?;
-    track(db, track_data).await
+pub async fn track_with_refs(db: &mut DbConn, track_data: &Track, pool: Pool) -> Result<()> {
+    let album_data = provider::get().album(track_data.album.id).await?;
+    albums(db, std::slice::from_ref(&album_data)).await?;
+    genres(db, &album_data.genres[..]).await?;
+    let pairs: Vec<_> = album_data
+        .genres
+        .iter()
+        .map(|genre| (album_data.id, genre.id))
+        .collect();
+    album_genres(db, &pairs).await?;
+    artists(db, std::slice::from_ref(&track_data.artist)).await?;
+    tracks(db, std::slice::from_ref(track_data), pool).await
 }
 
-/// Insert a track into the database, or update it if it already exists.
+/// Insert tracks into the database, or update them if they already exist.
 ///
-/// The referenced album and artist must already exist in the database.
-pub async fn track(db: &mut DbConn, track: &Track) -> Result<()> {
+/// The referenced albums and artists must already exist in the database. If a track
+/// is already in a more pickable pool, it stays there.
+pub async fn tracks(db: &mut DbConn, tracks: &[Track], pool: Pool) -> Result<()> {
+    if tracks.is_empty() {
+        return Ok(());
+    }
+    let ids: Vec<i64> = tracks.iter().map(|track| track.id.into()).collect();
+    let titles: Vec<&str> = tracks.iter().map(|track| track.title.as_str()).collect();
+    let links: Vec<&str> = tracks.iter().map(|track| track.link.as_str()).collect();
+    let previews: Vec<&str> = tracks.iter().map(|track| track.preview.as_str()).collect();
+    let ranks: Vec<i32> = tracks.iter().map(|track| track.rank).collect();
+    let album_ids: Vec<i64> = tracks.iter().map(|track| track.album.id.into()).collect();
+    let artist_ids: Vec<i64> = tracks.iter().map(|track| track.artist.id.into()).collect();
+    let durations: Vec<Option<i32>> = tracks.iter().map(|track| track.duration).collect();
+    let explicit: Vec<Option<bool>> = tracks.iter().map(|track| track.explicit_lyrics).collect();
+    let bpms: Vec<Option<f32>> = tracks.iter().map(|track| track.bpm).collect();
+    let isrcs: Vec<Option<&str>> = tracks.iter().map(|track| track.isrc.as_deref()).collect();
+    let readable: Vec<bool> = tracks.iter().map(|track| track.readable).collect();
+    // Postgres can't unnest an array of arrays into rows, so each track's countries are
+    // passed as a single comma separated string.
+    let countries: Vec<Option<String>> = tracks
+        .iter()
+        .map(|track| {
+            track
+                .available_countries
+                .as_ref()
+                .map(|codes| codes.join(","))
+        })
+        .collect();
+    // BPM, ISRC and available countries are only included in full track objects, and
+    // some providers don't know every track's duration or explicitness, so keep any
+    // existing values when updating from objects without them. The pick key is
+    // re-rolled on every refresh, so that no track keeps a key just after a large gap
+    // in key space (which would make it more likely to be picked) for ever.
     sqlx::query!(
-        "INSERT INTO track (id, title, deezer_url, preview_url, deezer_rank, album_id, artist_id) VALUES ($1, $2, $3, $4, $5, $6, $7)
+        "INSERT INTO track (
+            id, title, deezer_url, preview_url, deezer_rank, album_id, artist_id,
+            duration, explicit_lyrics, bpm, isrc, readable, available_countries, pool
+        )
+        SELECT
+            id, title, deezer_url, preview_url, deezer_rank, album_id, artist_id,
+            duration, explicit_lyrics, bpm, isrc, readable,
+            STRING_TO_ARRAY(available_countries, ','), $14
+        FROM UNNEST(
+            $1::BIGINT[], $2::TEXT[], $3::TEXT[], $4::TEXT[], $5::INTEGER[], $6::BIGINT[],
+            $7::BIGINT[], $8::INTEGER[], $9::BOOLEAN[], $10::REAL[], $11::TEXT[],
+            $12::BOOLEAN[], $13::TEXT[]
+        ) AS new (
+            id, title, deezer_url, preview_url, deezer_rank, album_id, artist_id,
+            duration, explicit_lyrics, bpm, isrc, readable, available_countries
+        )
         ON CONFLICT (id) DO UPDATE SET
+            pool = LEAST(track.pool, EXCLUDED.pool),
+            verified_at = TIMEZONE('utc', NOW()),
+            gone_at = NULL,
+            pick_key = RANDOM(),
             title = EXCLUDED.title,
             deezer_url = EXCLUDED.deezer_url,
             preview_url = EXCLUDED.preview_url,
             deezer_rank = EXCLUDED.deezer_rank,
             album_id = EXCLUDED.album_id,
-            artist_id = EXCLUDED.artist_id",
-        i32::from(track.id),
-        track.title,
-        track.link,
-        track.preview,
-        track.rank,
-        i32::from(track.album.id),
-        i32::from(track.artist.id),
-    ).execute(db).await.wrap_err("error inserting track")?;
+            artist_id = EXCLUDED.artist_id,
+            duration = COALESCE(EXCLUDED.duration, track.duration),
+            explicit_lyrics = COALESCE(EXCLUDED.explicit_lyrics, track.explicit_lyrics),
+            bpm = COALESCE(EXCLUDED.bpm, track.bpm),
+            isrc = COALESCE(EXCLUDED.isrc, track.isrc),
+            readable = EXCLUDED.readable,
+            available_countries = COALESCE(
+                EXCLUDED.available_countries, track.available_countries
+            )",
+        &ids,
+        &titles as &[&str],
+        &links as &[&str],
+        &previews as &[&str],
+        &ranks,
+        &album_ids,
+        &artist_ids,
+        &durations as &[Option<i32>],
+        &explicit as &[Option<bool>],
+        &bpms as &[Option<f32>],
+        &isrcs as &[Option<&str>],
+        &readable,
+        &countries as &[Option<String>],
+        pool as Pool,
+    )
+    .execute(db)
+    .await
+    .wrap_err("error inserting tracks")?;
+    Ok(())
+}
+
+/// Update the preview URL of a track, such as after the old one expired.
+pub async fn preview_url(db: &mut DbConn, track_id: deezer::Id, preview_url: &str) -> Result<()> {
+    sqlx::query!(
+        "UPDATE track SET preview_url = $1 WHERE id = $2",
+        preview_url,
+        i64::from(track_id),
+    )
+    .execute(db)
+    .await
+    .wrap_err("error updating track preview URL")?;
     Ok(())
 }
 
-/// Insert an album into the database, or update it if it already exists.
-pub async fn album(db: &mut DbConn, album: &Album) -> Result<()> {
+/// Insert albums into the database, or update them if they already exist.
+///
+/// This does not insert the albums' genres.
+pub async fn albums(db: &mut DbConn, albums: &[Album]) -> Result<()> {
+    if albums.is_empty() {
+        return Ok(());
+    }
+    let ids: Vec<i64> = albums.iter().map(|album| album.id.into()).collect();
+    let titles: Vec<&str> = albums.iter().map(|album| album.title.as_str()).collect();
+    let links: Vec<&str> = albums.iter().map(|album| album.link.as_str()).collect();
+    let covers: Vec<&str> = albums.iter().map(|album| album.cover.as_str()).collect();
+    let release_dates: Vec<Option<NaiveDate>> =
+        albums.iter().map(|album| album.release_date).collect();
+    let record_types: Vec<Option<&str>> = albums
+        .iter()
+        .map(|album| album.record_type.as_deref())
+        .collect();
     sqlx::query!(
-        "INSERT INTO album (id, title, deezer_url, cover_art_url) VALUES ($1, $2, $3, $4)
+        "INSERT INTO album (id, title, deezer_url, cover_art_url, release_date, record_type)
+        SELECT * FROM UNNEST(
+            $1::BIGINT[], $2::TEXT[], $3::TEXT[], $4::TEXT[], $5::DATE[], $6::TEXT[]
+        )
         ON CONFLICT (id) DO UPDATE SET
             title = EXCLUDED.title,
             deezer_url = EXCLUDED.deezer_url,
-            cover_art_url = EXCLUDED.cover_art_url",
-        i32::from(album.id),
-        album.title,
-        album.link,
-        album.cover,
+            cover_art_url = EXCLUDED.cover_art_url,
+            release_date = EXCLUDED.release_date,
+            record_type = EXCLUDED.record_type",
+        &ids,
+        &titles as &[&str],
+        &links as &[&str],
+        &covers as &[&str],
+        &release_dates as &[Option<NaiveDate>],
+        &record_types as &[Option<&str>],
     )
     .execute(db)
     .await
-    .wrap_err("error inserting album")?;
+    .wrap_err("error inserting albums")?;
     Ok(())
 }
 
-/// Insert a genre object into the database, or update it if it already exists.
-pub async fn genre(db: &mut DbConn, genre: &Genre) -> Result<()> {
+/// Insert genre objects into the database, or update them if they already exist.
+pub async fn genres(db: &mut DbConn, genres: &[Genre]) -> Result<()> {
+    if genres.is_empty() {
+        return Ok(());
+    }
+    let ids: Vec<i64> = genres.iter().map(|genre| genre.id.into()).collect();
+    let names: Vec<&str> = genres.iter().map(|genre| genre.name.as_str()).collect();
+    let pictures: Vec<&str> = genres.iter().map(|genre| genre.picture.as_str()).collect();
     sqlx::query!(
-        "INSERT INTO genre (id, title, picture_url) VALUES ($1, $2, $3)
+        "INSERT INTO genre (id, title, picture_url)
+        SELECT * FROM UNNEST($1::BIGINT[], $2::TEXT[], $3::TEXT[])
         ON CONFLICT (id) DO UPDATE SET
             title = EXCLUDED.title,
             picture_url = EXCLUDED.picture_url",
-        i32::from(genre.id),
-        genre.name,
-        genre.picture,
+        &ids,
+        &names as &[&str],
+        &pictures as &[&str],
     )
     .execute(db)
     .await
-    .wrap_err("error inserting genre")?;
+    .wrap_err("error inserting genres")?;
     Ok(())
 }
 
-/// Insert a many-to-many relationship between an album and a genre.
+/// Insert many-to-many relationships between albums and genres, as pairs of
+/// album and genre IDs.
 ///
-/// The referenced album and genre must already exist in the database.
+/// The referenced albums and genres must already exist in the database.
 ///
-/// If the relationship already exists, nothing will be done.
-pub async fn album_genre(
-    db: &mut DbConn,
-    album_id: deezer::Id,
-    genre_id: deezer::Id,
-) -> Result<()> {
+/// Relationships which already exist are left alone.
+pub async fn album_genres(db: &mut DbConn, pairs: &[(deezer::Id, deezer::Id)]) -> Result<()> {
+    if pairs.is_empty() {
+        return Ok(());
+    }
+    let album_ids: Vec<i64> = pairs.iter().map(|(album, _)| (*album).into()).collect();
+    let genre_ids: Vec<i64> = pairs.iter().map(|(_, genre)| (*genre).into()).collect();
     sqlx::query!(
-        "INSERT INTO album_genre (album_id, genre_id) VALUES ($1, $2) ON CONFLICT DO NOTHING",
-        i32::from(album_id),
-        i32::from(genre_id),
+        "INSERT INTO album_genre (album_id, genre_id)
+        SELECT * FROM UNNEST($1::BIGINT[], $2::BIGINT[])
+        ON CONFLICT DO NOTHING",
+        &album_ids,
+        &genre_ids,
     )
     .execute(db)
     .await
-    .wrap_err("error inserting album-genre relationship")?;
+    .wrap_err("error inserting album-genre relationships")?;
     Ok(())
 }
 
-/// Insert an artist into the database, or update it if it already exists.
-pub async fn artist(db: &mut DbConn, artist: &Artist) -> Result<()> {
+/// Insert artists into the database, or update them if they already exist.
+pub async fn artists(db: &mut DbConn, artists: &[Artist]) -> Result<()> {
+    if artists.is_empty() {
+        return Ok(());
+    }
+    let ids: Vec<i64> = artists.iter().map(|artist| artist.id.into()).collect();
+    let names: Vec<&str> = artists.iter().map(|artist| artist.name.as_str()).collect();
+    let links: Vec<&str> = artists.iter().map(|artist| artist.link.as_str()).collect();
+    let pictures: Vec<&str> = artists
+        .iter()
+        .map(|artist| artist.picture.as_str())
+        .collect();
+    sqlx::query!(
+        "INSERT INTO artist (id, title, deezer_url, picture_url)
+        SELECT * FROM UNNEST($1::BIGINT[], $2::TEXT[], $3::TEXT[], $4::TEXT[])
+        ON CONFLICT (id) DO UPDATE SET
+            title = EXCLUDED.title,
+            deezer_url = EXCLUDED.deezer_url,
+            picture_url = EXCLUDED.picture_url",
+        &ids,
+        &names as &[&str],
+        &links as &[&str],
+        &pictures as &[&str],
+    )
+    .execute(db)
+    .await
+    .wrap_err("error inserting artists")?;
+    Ok(())
+}
+
+/// Insert a playlist into the database, or update it if it already exists.
+///
+/// This does not insert the playlist's tracks.
+pub async fn playlist(db: &mut DbConn, playlist: &Playlist) -> Result<()> {
     sqlx::query!(
-        "INSERT INTO artist (id, title, deezer_url, picture_url) VALUES ($1, $2, $3, $4)
+        "INSERT INTO playlist (id, title, deezer_url, picture_url)
+        VALUES ($1, $2, $3, $4)
         ON CONFLICT (id) DO UPDATE SET
             title = EXCLUDED.title,
             deezer_url = EXCLUDED.deezer_url,
             picture_url = EXCLUDED.picture_url",
-        i32::from(artist.id),
-        artist.name,
-        artist.link,
-        artist.picture,
+        i64::from(playlist.id),
+        playlist.title,
+        playlist.link,
+        playlist.picture,
     )
     .execute(db)
     .await
-    .wrap_err("error inserting artist")?;
+    .wrap_err("error inserting playlist")?;
     Ok(())
 }
@@ -1,5 +1,6 @@
 //! Get track metadata to return in the API.
 use crate::{deezer, DbConn};
+use chrono::NaiveDate;
 use eyre::{Context, Result};
 use serde::Serialize;
 
@@ -19,6 +20,18 @@ pub struct Meta {
     album_title: String,
     /// URL of an image of the album cover art
     album_cover: String,
+    /// Track length in seconds, if known
+    pub duration: Option<i32>,
+    /// Whether the track has explicit lyrics, if known
+    pub explicit_lyrics: Option<bool>,
+    /// Beats per minute, if known
+    bpm: Option<f32>,
+    /// International Standard Recording Code, if known
+    isrc: Option<String>,
+    /// The album's release date, if known
+    pub release_date: Option<NaiveDate>,
+    /// The kind of album release, such as `album`, `single`, `ep` or `compile`, if known
+    record_type: Option<String>,
 }
 
 impl Meta {
@@ -32,12 +45,18 @@ impl Meta {
                 track.deezer_url AS link,
                 artist.title AS artist_name,
                 album.title AS album_title,
-                album.cover_art_url AS album_cover
+                album.cover_art_url AS album_cover,
+                track.duration,
+                track.explicit_lyrics,
+                track.bpm,
+                track.isrc,
+                album.release_date,
+                album.record_type
             FROM track
             INNER JOIN artist ON track.artist_id = artist.id
             INNER JOIN album ON track.album_id = album.id
             WHERE track.id = $1",
-            i32::from(id),
+            i64::from(id),
         )
         .fetch_optional(db)
         .await
@@ -62,6 +81,13 @@ impl From<deezer::Track> for Meta {
             artist_name: track.artist.name,
             album_title: track.album.title,
             album_cover: track.album.cover,
+            duration: track.duration,
+            explicit_lyrics: track.explicit_lyrics,
+            bpm: track.bpm,
+            isrc: track.isrc,
+            // partial album objects don't include these
+            release_date: None,
+            record_type: None,
         }
     }
 }
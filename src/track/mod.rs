@@ -1,27 +1,103 @@
 //! Tools for working with the music data in the database.
-use crate::{deezer, DbConn};
+use crate::{deezer, provider, DbConn};
 use eyre::{Context, Result};
+use std::sync::OnceLock;
 
 mod bulk_insert;
 mod insert;
 mod meta;
 mod music;
 pub mod pick;
+pub mod refresh;
 mod routes;
 mod similar;
 
 pub use meta::Meta;
-pub use music::init;
 pub use routes::routes;
 pub use similar::similar;
 
+/// Where a track in the database came from, which decides whether it may be picked.
+///
+/// Variants are ordered from most to least pickable, matching the database enum, and
+/// a track is only ever moved to a more pickable pool.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+#[sqlx(type_name = "track_pool", rename_all = "snake_case")]
+pub enum Pool {
+    /// Fetched from a genre chart.
+    Chart,
+    /// Only inserted because someone guessed it, so never picked.
+    Guess,
+}
+
+/// The country players are in, if configured, set on startup.
+static REGION: OnceLock<Option<String>> = OnceLock::new();
+
+/// Initialise the music cache system and region using the given config.
+///
+/// Must only be called once.
+pub fn init(config: &crate::Config) {
+    music::init(config);
+    REGION
+        .set(config.region.as_ref().map(|region| region.to_uppercase()))
+        .expect("track::init must only be called once");
+}
+
+/// The country players are in, as an uppercase ISO 3166-1 alpha-2 code, if configured.
+fn region() -> Option<&'static str> {
+    REGION
+        .get()
+        .expect("region used before initialisation")
+        .as_deref()
+}
+
+impl deezer::Track {
+    /// Whether the track can be streamed by players, as far as we know.
+    pub fn is_available(&self) -> bool {
+        match (region(), &self.available_countries) {
+            (Some(region), Some(countries)) => {
+                self.readable && countries.iter().any(|country| country == region)
+            }
+            _ => self.readable,
+        }
+    }
+}
+
+/// Make sure each of the given genres is in the database, adding any which the provider
+/// lists but which aren't stored yet. Returns false if any of the genres don't exist.
+pub async fn ensure_genres(db: &mut DbConn, ids: &[deezer::Id]) -> Result<bool> {
+    let ids: Vec<i64> = ids.iter().copied().map(i64::from).collect();
+    let stored = sqlx::query_scalar!("SELECT id FROM genre WHERE id = ANY($1)", &ids)
+        .fetch_all(&mut *db)
+        .await
+        .wrap_err("error querying which genres exist")?;
+    let missing: Vec<deezer::Id> = ids
+        .into_iter()
+        .filter(|id| !stored.contains(id))
+        .map(deezer::Id::from)
+        .collect();
+    if missing.is_empty() {
+        return Ok(true);
+    }
+    let listed: Vec<_> = provider::get()
+        .genres()
+        .await?
+        .into_iter()
+        .filter(|genre| missing.contains(&genre.id))
+        .collect();
+    if listed.len() < missing.len() {
+        return Ok(false);
+    }
+    insert::genres(db, &listed).await?;
+    Ok(true)
+}
+
 /// Get a genre object from the database by ID.
 pub async fn genre(db: &mut DbConn, id: deezer::Id) -> Result<deezer::Genre> {
     let genre = sqlx::query_as!(
         deezer::Genre,
         "SELECT id, title AS name, picture_url AS picture FROM genre
         WHERE id = $1",
-        i32::from(id),
+        i64::from(id),
     )
     .fetch_one(db)
     .await
@@ -29,6 +105,34 @@ pub async fn genre(db: &mut DbConn, id: deezer::Id) -> Result<deezer::Genre> {
     Ok(genre)
 }
 
+/// Get an artist object from the database by ID.
+pub async fn artist(db: &mut DbConn, id: deezer::Id) -> Result<deezer::Artist> {
+    let artist = sqlx::query_as!(
+        deezer::Artist,
+        "SELECT id, title AS name, deezer_url AS link, picture_url AS picture FROM artist
+        WHERE id = $1",
+        i64::from(id),
+    )
+    .fetch_one(db)
+    .await
+    .wrap_err("error querying artist")?;
+    Ok(artist)
+}
+
+/// Get a playlist object from the database by ID.
+pub async fn playlist(db: &mut DbConn, id: deezer::Id) -> Result<deezer::Playlist> {
+    let playlist = sqlx::query_as!(
+        deezer::Playlist,
+        "SELECT id, title, deezer_url AS link, picture_url AS picture FROM playlist
+        WHERE id = $1",
+        i64::from(id),
+    )
+    .fetch_one(db)
+    .await
+    .wrap_err("error querying playlist")?;
+    Ok(playlist)
+}
+
 /// Get a clip of music from a track.
 pub async fn clip(
     db: &mut DbConn,
@@ -37,24 +141,25 @@ pub async fn clip(
 ) -> Result<Vec<u8>> {
     let preview_url = sqlx::query_scalar!(
         "SELECT preview_url FROM track WHERE id = $1",
-        i32::from(track_id),
+        i64::from(track_id),
     )
-    .fetch_one(db)
+    .fetch_one(&mut *db)
     .await
     .wrap_err("error querying track preview URL")?;
-    music::clip(track_id.0, &preview_url, time)
+    music::clip(db, track_id.0, &preview_url, time)
         .await
         .wrap_err("error clipping music")
 }
 
-/// Get the given track from the database, or fetch it from Deezer if it's not there.
+/// Get the given track from the database, or fetch it from the music provider if it's
+/// not there.
 pub async fn get_or_fetch(db: &mut DbConn, id: deezer::Id) -> Result<Option<Meta>> {
     if let Some(track) = Meta::try_get(db, id).await? {
         return Ok(Some(track));
     }
-    match deezer::track(id).await? {
+    match provider::get().track(id).await? {
         Some(track) => {
-            insert::track_with_refs(db, &track)
+            insert::track_with_refs(db, &track, Pool::Guess)
                 .await
                 .wrap_err("inserting track into database with references")?;
             Ok(Some(track.into()))
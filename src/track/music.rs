@@ -1,4 +1,4 @@
-//! A cache system for storing preview MP3s from Deezer, and retrieving clips from them.
+//! A cache system for storing preview MP3s from the music provider, and retrieving clips from them.
 use std::{ops::Range, sync::OnceLock};
 
 use eyre::{Context, Result};
@@ -7,10 +7,11 @@ use futures::TryStreamExt;
 use minimp3_fixed as minimp3;
 use rocket::{
     http::hyper::body::Bytes,
-    tokio::{fs, task},
+    tokio::{self, fs, io::AsyncBufReadExt, task},
 };
 
-use crate::deezer;
+use super::insert;
+use crate::{deezer, provider, DbConn};
 
 /// The config for the music cache system, set on startup.
 static CONFIG: OnceLock<Config> = OnceLock::new();
@@ -41,53 +42,186 @@ impl From<&crate::Config> for Config {
     }
 }
 
-/// Transcode a downloaded track from MP3 to WAV, and save it.
+/// Clips never extend past 30 seconds (the length of a Deezer preview), so longer
+/// tracks (such as full songs from a local library) are truncated when cached.
+const MAX_CACHED_SECONDS: usize = 30;
+
+/// A writer for a cached track, which always stores 16 bit stereo audio and stops
+/// accepting samples once [`MAX_CACHED_SECONDS`] have been written.
+struct CacheWriter {
+    /// The underlying WAV writer.
+    writer: hound::WavWriter<std::io::BufWriter<std::fs::File>>,
+    /// The number of channels in the decoded audio (1 or 2).
+    channels: u16,
+    /// How many more individual sample values may be written.
+    remaining: usize,
+}
+
+impl CacheWriter {
+    /// Create a cache file for audio with the given number of channels and sample rate.
+    fn create(path: &std::path::Path, channels: u16, sample_rate: u32) -> Result<Self> {
+        if !(1..=2).contains(&channels) {
+            return Err(eyre::eyre!("unsupported channel count: {channels}"));
+        }
+        let wav_spec = hound::WavSpec {
+            channels: 2,
+            sample_rate,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let file =
+            std::fs::File::create(path).wrap_err("error creating a file for decoded music")?;
+        let writer = hound::WavWriter::new(std::io::BufWriter::new(file), wav_spec)
+            .wrap_err("error creating a WAV writer")?;
+        let sample_rate = usize::try_from(sample_rate).expect("sample rate should fit in usize");
+        Ok(Self {
+            writer,
+            channels,
+            remaining: 2 * sample_rate * MAX_CACHED_SECONDS,
+        })
+    }
+
+    /// Write interleaved samples, returning `false` once the cache is full.
+    fn write(&mut self, samples: impl IntoIterator<Item = i16>) -> Result<bool> {
+        for sample in samples {
+            let copies = if self.channels == 1 { 2 } else { 1 };
+            for _ in 0..copies {
+                if self.remaining == 0 {
+                    return Ok(false);
+                }
+                self.writer
+                    .write_sample(sample)
+                    .wrap_err("error writing a sample to a WAV file")?;
+                self.remaining -= 1;
+            }
+        }
+        Ok(true)
+    }
+
+    /// Finish writing the cache file.
+    fn finalize(self) -> Result<()> {
+        self.writer.finalize().map_err(Into::into)
+    }
+}
+
+/// Transcode a downloaded track from MP3 or FLAC to WAV, and save it.
 async fn save_track(
     path: std::path::PathBuf,
-    mp3_stream: impl Stream<Item = Result<Bytes>> + Send,
+    stream: impl Stream<Item = Result<Bytes>> + Send + 'static,
 ) -> Result<()> {
-    let mp3_read = Box::pin(tokio_util::io::StreamReader::new(
-        mp3_stream.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e)),
+    let mut reader = Box::pin(tokio_util::io::StreamReader::new(
+        stream.map_err(std::io::Error::other),
     ));
-    let mut decoder = minimp3::Decoder::new(mp3_read);
+    let is_flac = reader
+        .fill_buf()
+        .await
+        .wrap_err("error reading start of track")?
+        .starts_with(b"fLaC");
+    if is_flac {
+        let reader = tokio_util::io::SyncIoBridge::new(reader);
+        task::spawn_blocking(move || save_flac(&path, reader)).await?
+    } else {
+        save_mp3(&path, reader).await
+    }
+}
+
+/// Transcode an MP3 stream to WAV, and save it.
+async fn save_mp3(
+    path: &std::path::Path,
+    reader: impl tokio::io::AsyncRead + Unpin + Send,
+) -> Result<()> {
+    let mut decoder = minimp3::Decoder::new(reader);
     let first_frame = decoder
         .next_frame_future()
         .await
         .wrap_err("error reading first frame of MP3")?;
-    let wav_spec = hound::WavSpec {
-        channels: u16::try_from(first_frame.channels).wrap_err("invalid channel count")?,
-        sample_rate: u32::try_from(first_frame.sample_rate).wrap_err("negative sample rate")?,
-        bits_per_sample: 16,
-        sample_format: hound::SampleFormat::Int,
-    };
-    let file = std::fs::File::create(path).wrap_err("error creating a file for decoded music")?;
-    let mut writer = hound::WavWriter::new(std::io::BufWriter::new(file), wav_spec)
-        .wrap_err("error creating a WAV writer")?;
+    let mut writer = CacheWriter::create(
+        path,
+        u16::try_from(first_frame.channels).wrap_err("invalid channel count")?,
+        u32::try_from(first_frame.sample_rate).wrap_err("negative sample rate")?,
+    )?;
     let mut maybe_frame = Ok(first_frame);
     loop {
         let frame = match maybe_frame {
             Ok(frame) => frame,
-            Err(minimp3::Error::Eof) => return writer.finalize().map_err(Into::into),
+            Err(minimp3::Error::Eof) => return writer.finalize(),
             Err(err) => return Err(err.into()),
         };
-        for sample in frame.data {
-            writer
-                .write_sample(sample)
-                .wrap_err("error writing a sample to a WAV file")?;
+        if !writer.write(frame.data)? {
+            return writer.finalize();
         }
         maybe_frame = decoder.next_frame_future().await;
     }
 }
 
-/// Download a track from Deezer and save it to the music cache.
-async fn download_track(config: &Config, track_id: u32, preview: &str) -> Result<()> {
-    let data = deezer::track_preview(preview).await?;
+/// Transcode a FLAC stream to WAV, and save it (blocking).
+fn save_flac(path: &std::path::Path, reader: impl std::io::Read) -> Result<()> {
+    let mut reader = claxon::FlacReader::new(reader).wrap_err("error reading FLAC header")?;
+    let info = reader.streaminfo();
+    let bits = info.bits_per_sample;
+    let mut writer = CacheWriter::create(
+        path,
+        u16::try_from(info.channels).wrap_err("invalid channel count")?,
+        info.sample_rate,
+    )?;
+    let mut samples = reader.samples();
+    loop {
+        let chunk = samples
+            .by_ref()
+            .take(4096)
+            .map(|sample| sample.map(|sample| to_i16(sample, bits)))
+            .collect::<Result<Vec<_>, _>>()
+            .wrap_err("error decoding FLAC samples")?;
+        if chunk.is_empty() || !writer.write(chunk)? {
+            return writer.finalize();
+        }
+    }
+}
+
+/// Convert a sample of the given bit depth to a 16 bit sample.
+#[allow(clippy::cast_possible_truncation)]
+const fn to_i16(sample: i32, bits: u32) -> i16 {
+    if bits > 16 {
+        (sample >> (bits - 16)) as i16
+    } else {
+        (sample << (16 - bits)) as i16
+    }
+}
+
+/// Download a track from the music provider and save it to the music cache.
+///
+/// If the preview URL has expired, fetch the track again for a new one, save it to the
+/// database and retry.
+async fn download_track(
+    db: &mut DbConn,
+    config: &Config,
+    track_id: u64,
+    preview: &str,
+) -> Result<()> {
+    let data = match provider::get().track_preview(preview).await {
+        Err(e) if e.downcast_ref::<provider::PreviewExpired>().is_some() => {
+            let track = provider::get()
+                .track_uncached(deezer::Id(track_id))
+                .await?
+                .ok_or_else(|| eyre::eyre!("track with an expired preview no longer exists"))?;
+            insert::preview_url(db, track.id, &track.preview).await?;
+            provider::get()
+                .track_preview(&track.preview)
+                .await
+                .wrap_err("error downloading a track preview, right after refreshing it")?
+        }
+        result => result?,
+    };
     let path = config.music_dir.join(format!("{track_id}.wav"));
     save_track(path, data).await
 }
 
 /// Ensure that a given track is cached, and return the path.
-async fn ensure_cached(track_id: u32, preview: &str) -> Result<std::path::PathBuf> {
+async fn ensure_cached(
+    db: &mut DbConn,
+    track_id: u64,
+    preview: &str,
+) -> Result<std::path::PathBuf> {
     let config = CONFIG
         .get()
         .expect("music system used before initialisation");
@@ -96,7 +230,7 @@ async fn ensure_cached(track_id: u32, preview: &str) -> Result<std::path::PathBu
         .await
         .wrap_err("error checking if a track is cached")?
     {
-        download_track(config, track_id, preview).await?;
+        download_track(db, config, track_id, preview).await?;
     }
     Ok(path)
 }
@@ -115,7 +249,7 @@ fn blocking_clip_track(path: std::path::PathBuf, time: Range<chrono::Duration>)
     let mut buf = Vec::new();
     let cursor = std::io::Cursor::new(&mut buf);
     let mut writer = hound::WavWriter::new(cursor, spec).wrap_err("error creating a WAV writer")?;
-    assert_eq!(spec.channels, 2, "Deezer should return stereo music");
+    assert_eq!(spec.channels, 2, "cached music should always be stereo");
     let sample_rate = usize::try_from(spec.sample_rate).expect("sample rate should fit in usize");
     let samples_to_read = usize::from(spec.channels)
         * sample_rate
@@ -153,7 +287,12 @@ fn blocking_clip_track(path: std::path::PathBuf, time: Range<chrono::Duration>)
 
 /// Get a clip from a track.
 /// The clip is returned as a vector of bytes in WAV format.
-pub async fn clip(track_id: u32, preview: &str, time: Range<chrono::Duration>) -> Result<Vec<u8>> {
-    let path = ensure_cached(track_id, preview).await?;
+pub async fn clip(
+    db: &mut DbConn,
+    track_id: u64,
+    preview: &str,
+    time: Range<chrono::Duration>,
+) -> Result<Vec<u8>> {
+    let path = ensure_cached(db, track_id, preview).await?;
     task::spawn_blocking(move || blocking_clip_track(path, time)).await?
 }
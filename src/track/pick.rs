@@ -1,22 +1,108 @@
 //! Procedures to pick a track for a new game.
 use eyre::eyre;
 
-use super::bulk_insert::BulkInserter as BulkTrackInserter;
-use crate::{deezer, DbConn};
+use super::refresh;
+use crate::{deezer, DbConn, User};
+use chrono::NaiveDate;
 use eyre::{Context, Result};
+use serde::{Deserialize, Serialize};
 
-/// Pick any track from the database, preferring more popular tracks.
+/// How many rank buckets to sample from before falling back to any unplayed track.
+const BUCKET_ATTEMPTS: usize = 3;
+
+/// The genre ID used for the pick weights of all tracks. This is the "all genres" ID
+/// of music provider charts, which is never stored as a real genre.
+const ALL_GENRES: i64 = 0;
+
+/// If an era has fewer tracks than this to pick from, fetch more before picking.
+const MIN_ERA_TRACKS: i64 = 50;
+
+/// A range of years, inclusive, which a game's track was released in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct Era {
+    /// The first year of the era.
+    pub start_year: i32,
+    /// The last year of the era.
+    pub end_year: i32,
+}
+
+impl Era {
+    /// Get the era for the decade starting with the given year, such as 1990.
+    ///
+    /// Returns `None` if the end of the decade is out of range.
+    pub const fn decade(start_year: i32) -> Option<Self> {
+        match start_year.checked_add(9) {
+            Some(end_year) => Some(Self {
+                start_year,
+                end_year,
+            }),
+            None => None,
+        }
+    }
+
+    /// The first day of the era.
+    fn first_day(self) -> NaiveDate {
+        NaiveDate::from_ymd_opt(self.start_year, 1, 1).unwrap_or(NaiveDate::MIN)
+    }
+
+    /// The last day of the era.
+    fn last_day(self) -> NaiveDate {
+        NaiveDate::from_ymd_opt(self.end_year, 12, 31).unwrap_or(NaiveDate::MAX)
+    }
+}
+
+/// How strongly to prefer popular tracks when picking a track for a game.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, sqlx::Type, Serialize, Deserialize)]
+#[sqlx(type_name = "game_difficulty", rename_all = "snake_case")]
+#[serde(rename_all = "camelCase")]
+pub enum Difficulty {
+    /// Strongly prefer popular tracks, weighting by the square of their rank.
+    Mainstream,
+    /// Prefer popular tracks, weighting by rank.
+    #[default]
+    Balanced,
+    /// Prefer less popular tracks, weighting by the inverse of rank.
+    DeepCuts,
+}
+
+impl Difficulty {
+    /// The weight to give a rank bucket, given the total rank and number of tracks in
+    /// it. Tracks in a bucket have ranks of roughly `2^bucket`.
+    fn bucket_weight(self, bucket: i16, rank_sum: i64, track_count: i64) -> f64 {
+        let scale = 2f64.powi(bucket.into());
+        #[allow(clippy::cast_precision_loss)]
+        match self {
+            Self::Mainstream => rank_sum as f64 * scale,
+            Self::Balanced => rank_sum as f64,
+            Self::DeepCuts => track_count as f64 / scale,
+        }
+    }
+
+    /// The power of its rank to weight each track by, matching [`Self::bucket_weight`].
+    const fn rank_exponent(self) -> f64 {
+        match self {
+            Self::Mainstream => 2.0,
+            Self::Balanced => 1.0,
+            Self::DeepCuts => -1.0,
+        }
+    }
+}
+
+/// Pick any track from the database, preferring popular tracks as much as the
+/// difficulty says.
 ///
 /// If no tracks are available, get fresh data and try again. Avoid tracks the
 /// given user has recently played.
-pub async fn any(db: &mut DbConn, user: i32) -> Result<deezer::Id> {
-    if let Some(track) = try_pick_any(db, user).await? {
+pub async fn any(db: &mut DbConn, user: &User, difficulty: Difficulty) -> Result<deezer::Id> {
+    let filter = Filter::new(&[], None, user.allows_explicit());
+    if let Some(track) = try_pick(db, &filter, user.id, difficulty).await? {
         return Ok(track);
     }
-    refresh_all(db)
+    refresh::all(db)
         .await
         .wrap_err("error fetching new tracks for the database")?;
-    if let Some(track) = try_pick_any(db, user)
+    if let Some(track) = try_pick(db, &filter, user.id, difficulty)
         .await
         .wrap_err("error trying to find any track in the database, right after refresh")?
     {
@@ -25,80 +111,391 @@ pub async fn any(db: &mut DbConn, user: i32) -> Result<deezer::Id> {
     Err(eyre!("couldn't find any track"))
 }
 
-/// Pick any track from the database, preferring more popular tracks and
-/// avoiding tracks the given user has recently played.
-async fn try_pick_any(db: &mut DbConn, user: i32) -> Result<Option<deezer::Id>> {
-    loop {
-        let Some(track) = sqlx::query_scalar!(
-            "SELECT track.id FROM track
-            LEFT JOIN game ON track.id = game.track_id AND game.account_id = $1
-            ORDER BY
-                game.started_at ASC NULLS FIRST,
-                RANDOM() * track.deezer_rank DESC
-            LIMIT 1",
-            user
-        )
-        .fetch_optional(&mut *db)
-        .await
-        .wrap_err("error querying for a random track")?
-        .map(From::from) else {
-            return Ok(None);
-        };
-        if let Some(track) = ensure_exists(db, track).await? {
-            return Ok(Some(track));
-        }
-    }
-}
-
-/// Pick a track from the specified genre, preferring more popular tracks.
+/// Pick a track from any of the specified genres, preferring popular tracks as much
+/// as the difficulty says.
 ///
-/// If no tracks are available, get fresh data and try again. Avoid tracks the
-/// given user has recently played.
-pub async fn genre(db: &mut DbConn, genre_id: deezer::Id, user: i32) -> Result<deezer::Id> {
-    if let Some(track) = try_pick_genre(db, genre_id, user).await? {
+/// If no tracks are available, get fresh data for each genre and try again. Avoid
+/// tracks the given user has recently played.
+pub async fn genres(
+    db: &mut DbConn,
+    genre_ids: &[deezer::Id],
+    user: &User,
+    difficulty: Difficulty,
+) -> Result<deezer::Id> {
+    let filter = Filter::new(genre_ids, None, user.allows_explicit());
+    if let Some(track) = try_pick(db, &filter, user.id, difficulty).await? {
         return Ok(track);
     }
-    refresh_genre(db, genre_id).await?;
-    if let Some(track) = try_pick_genre(db, genre_id, user)
+    for genre_id in genre_ids {
+        refresh::genre(db, *genre_id).await?;
+    }
+    if let Some(track) = try_pick(db, &filter, user.id, difficulty)
         .await
-        .wrap_err("error trying to find a track in the specified genre, right after refresh")?
+        .wrap_err("error trying to find a track in the specified genres, right after refresh")?
     {
         return Ok(track);
     }
-    Err(eyre!("couldn't find any track in the specified genre"))
+    Err(eyre!("couldn't find any track in the specified genres"))
 }
 
-/// Pick a track from the specified genre, preferring more popular tracks and
-/// avoiding tracks the given user has recently played.
-async fn try_pick_genre(
+/// Pick a track released in the specified era, optionally also from any of a set of
+/// genres, preferring popular tracks as much as the difficulty says.
+///
+/// If there are fewer than [`MIN_ERA_TRACKS`] tracks to pick from, get fresh data for
+/// the era first. Avoid tracks the given user has recently played. Returns `None` if
+/// there are no tracks in the era which can be picked for the user.
+pub async fn era(
     db: &mut DbConn,
-    genre_id: deezer::Id,
-    user: i32,
+    era: Era,
+    genre_ids: &[deezer::Id],
+    user: &User,
+    difficulty: Difficulty,
+) -> Result<Option<deezer::Id>> {
+    let filter = Filter::new(genre_ids, Some(era), user.allows_explicit());
+    if filter.count(db).await? < MIN_ERA_TRACKS {
+        refresh::era(db, era).await?;
+    }
+    try_pick(db, &filter, user.id, difficulty)
+        .await
+        .wrap_err("error trying to find a track in the specified era")
+}
+
+/// Pick one of the top tracks of an artist, and optionally of related artists,
+/// preferring popular tracks as much as the difficulty says.
+///
+/// The tracks are always fetched fresh from the provider, which caches them. Avoid
+/// tracks the given user has recently played. Returns `None` if the artist doesn't
+/// exist, or `Some(None)` if it has no tracks which can be picked for the user.
+pub async fn artist(
+    db: &mut DbConn,
+    artist_id: deezer::Id,
+    related: bool,
+    user: &User,
+    difficulty: Difficulty,
+) -> Result<Option<Option<deezer::Id>>> {
+    let Some(tracks) = refresh::artist(db, artist_id, related).await? else {
+        return Ok(None);
+    };
+    pick_among(db, &tracks, user, difficulty).await.map(Some)
+}
+
+/// Pick one of the tracks in a playlist, preferring popular tracks as much as the
+/// difficulty says. Returns `None` if the playlist doesn't exist, or `Some(None)` if
+/// it has no tracks which can be picked for the user.
+///
+/// The tracks are always fetched fresh from the provider. Avoid tracks the given user
+/// has recently played.
+pub async fn playlist(
+    db: &mut DbConn,
+    playlist_id: deezer::Id,
+    user: &User,
+    difficulty: Difficulty,
+) -> Result<Option<Option<deezer::Id>>> {
+    let Some(tracks) = refresh::playlist(db, playlist_id).await? else {
+        return Ok(None);
+    };
+    pick_among(db, &tracks, user, difficulty).await.map(Some)
+}
+
+/// Pick one of a small set of tracks, preferring popular tracks as much as the
+/// difficulty says and avoiding tracks the given user has recently played. Tracks which
+/// are unavailable in the configured region are never picked, and nor are explicit
+/// tracks unless the user allows them.
+async fn pick_among(
+    db: &mut DbConn,
+    tracks: &[deezer::Id],
+    user: &User,
+    difficulty: Difficulty,
 ) -> Result<Option<deezer::Id>> {
-    loop {
-        let Some(track) = sqlx::query_scalar!(
-            "SELECT track.id FROM track
-            INNER JOIN album ON track.album_id = album.id
-            INNER JOIN album_genre ON album.id = album_genre.album_id
-            LEFT JOIN game ON track.id = game.track_id AND game.account_id = $2
-            WHERE album_genre.genre_id = $1
-            ORDER BY
-                game.started_at ASC NULLS FIRST,
-                RANDOM() * track.deezer_rank DESC
-            LIMIT 1",
-            i32::from(genre_id),
-            user
+    let tracks: Vec<i64> = tracks.iter().copied().map(i64::from).collect();
+    // There are few enough tracks that sorting them all is fine. Ordering by
+    // `ln(u) / weight` for uniform random `u` samples weighted by `weight`.
+    let track = sqlx::query_scalar!(
+        r#"SELECT track.id AS "id!" FROM playable_track($4, $5) AS track
+        WHERE track.id = ANY($1)
+        ORDER BY
+            (
+                SELECT MAX(game.started_at) FROM game
+                WHERE game.account_id = $2 AND game.track_id = track.id
+            ) ASC NULLS FIRST,
+            LN(1 - RANDOM()) / POWER(GREATEST(track.deezer_rank, 1), $3) DESC
+        LIMIT 1"#,
+        &tracks,
+        user.id,
+        difficulty.rank_exponent(),
+        user.allows_explicit(),
+        super::region(),
+    )
+    .fetch_optional(db)
+    .await
+    .wrap_err("error querying for one of a set of tracks")?;
+    Ok(track.map(From::from))
+}
+
+/// Which tracks may be picked for a game.
+///
+/// These are the arguments to the `pickable_track` SQL function, which every picking
+/// query selects from.
+struct Filter {
+    /// The genres tracks must be in any of, or empty to allow any genre.
+    genre_ids: Vec<i64>,
+    /// The first and last days tracks' albums may have been released on, if restricted.
+    released: Option<(NaiveDate, NaiveDate)>,
+    /// Whether tracks with explicit lyrics may be picked.
+    allow_explicit: bool,
+    /// The country tracks must be available in, if configured.
+    region: Option<&'static str>,
+}
+
+impl Filter {
+    /// Create a filter for tracks from any of a set of genres (or any genre, if
+    /// empty) and an optional era, optionally excluding explicit tracks.
+    fn new(genre_ids: &[deezer::Id], era: Option<Era>, allow_explicit: bool) -> Self {
+        Self {
+            genre_ids: genre_ids.iter().copied().map(i64::from).collect(),
+            released: era.map(|era| (era.first_day(), era.last_day())),
+            allow_explicit,
+            region: super::region(),
+        }
+    }
+
+    /// The first day of the release window, or `None` if unrestricted.
+    fn released_from(&self) -> Option<NaiveDate> {
+        self.released.map(|(from, _)| from)
+    }
+
+    /// The last day of the release window, or `None` if unrestricted.
+    fn released_until(&self) -> Option<NaiveDate> {
+        self.released.map(|(_, until)| until)
+    }
+
+    /// The genre ID the pick weights for this filter are precomputed under, or `None`
+    /// if they aren't.
+    fn pick_weight_genre(&self) -> Option<i64> {
+        if self.released.is_some() {
+            return None;
+        }
+        match self.genre_ids.as_slice() {
+            [] => Some(ALL_GENRES),
+            [genre_id] => Some(*genre_id),
+            _ => None,
+        }
+    }
+
+    /// Count the tracks which can be picked with this filter.
+    async fn count(&self, db: &mut DbConn) -> Result<i64> {
+        sqlx::query_scalar!(
+            r#"SELECT COUNT(*) AS "count!" FROM pickable_track($1, $2, $3, $4, $5)"#,
+            &self.genre_ids,
+            self.released_from(),
+            self.released_until(),
+            self.allow_explicit,
+            self.region,
         )
-        .fetch_optional(&mut *db)
+        .fetch_one(db)
         .await
-        .wrap_err("error querying for a random track in the specified genre")?
-        .map(From::from) else {
-            return Ok(None);
+        .wrap_err("error counting pickable tracks")
+    }
+}
+
+/// Pick a track matching a filter, weighted by rank according to the difficulty and
+/// avoiding tracks the given user has played.
+///
+/// Rather than sorting every track, this picks a rank bucket weighted by the tracks in
+/// it (see [`refresh::pick_weights`] and [`Difficulty::bucket_weight`]), then seeks to
+/// a random point in the bucket by each track's random `pick_key`. Tracks within a
+/// bucket have ranks within a factor of two of each other and are picked uniformly,
+/// which approximates weighting each track individually. If there is no unplayed track
+/// after the random point, wrap around to the start of the bucket. If sampling keeps
+/// landing on buckets with only played tracks, fall back to any unplayed track, and then
+/// to the track the user played least recently.
+async fn try_pick(
+    db: &mut DbConn,
+    filter: &Filter,
+    user: i32,
+    difficulty: Difficulty,
+) -> Result<Option<deezer::Id>> {
+    let weights = bucket_weights(db, filter, difficulty).await?;
+    for _ in 0..BUCKET_ATTEMPTS {
+        let Some(bucket) = choose_bucket(&weights) else {
+            break;
         };
-        if let Some(track) = ensure_exists(db, track).await? {
+        for from_key in [rand::random(), 0.0] {
+            if let Some(track) = sample_bucket(db, filter, user, bucket, from_key).await? {
+                return Ok(Some(track));
+            }
+        }
+    }
+    for from_key in [rand::random(), 0.0] {
+        if let Some(track) = sample_unplayed(db, filter, user, from_key).await? {
             return Ok(Some(track));
         }
     }
+    least_recently_played(db, filter, user).await
+}
+
+/// Get the weight of each rank bucket according to the difficulty.
+///
+/// Weights are precomputed for each genre, with and without explicit tracks, but not for
+/// eras or sets of genres, so those are calculated from the matching tracks. They are
+/// also calculated if none have been precomputed yet, such as for a newly fetched genre.
+async fn bucket_weights(
+    db: &mut DbConn,
+    filter: &Filter,
+    difficulty: Difficulty,
+) -> Result<Vec<(i16, f64)>> {
+    let precomputed: Vec<(i16, i64, i64)> = match filter.pick_weight_genre() {
+        Some(genre_id) => sqlx::query!(
+            "SELECT bucket, weight, track_count FROM pick_weight
+            WHERE genre_id = $1 AND allow_explicit = $2",
+            genre_id,
+            filter.allow_explicit,
+        )
+        .fetch_all(&mut *db)
+        .await
+        .wrap_err("error querying track pick weights")?
+        .into_iter()
+        .map(|row| (row.bucket, row.weight, row.track_count))
+        .collect(),
+        None => Vec::new(),
+    };
+    let rows = if precomputed.is_empty() {
+        sqlx::query!(
+            r#"SELECT
+                track.pick_bucket AS "bucket!",
+                SUM(track.deezer_rank) AS "weight!",
+                COUNT(*) AS "track_count!"
+            FROM pickable_track($1, $2, $3, $4, $5) AS track
+            GROUP BY track.pick_bucket"#,
+            &filter.genre_ids,
+            filter.released_from(),
+            filter.released_until(),
+            filter.allow_explicit,
+            filter.region,
+        )
+        .fetch_all(db)
+        .await
+        .wrap_err("error calculating track pick weights")?
+        .into_iter()
+        .map(|row| (row.bucket, row.weight, row.track_count))
+        .collect()
+    } else {
+        precomputed
+    };
+    Ok(rows
+        .into_iter()
+        .map(|(bucket, weight, track_count)| {
+            (
+                bucket,
+                difficulty.bucket_weight(bucket, weight, track_count),
+            )
+        })
+        .collect())
+}
+
+/// Choose a rank bucket at random, weighted by the given bucket weights.
+fn choose_bucket(weights: &[(i16, f64)]) -> Option<i16> {
+    let total: f64 = weights.iter().map(|(_, weight)| weight).sum();
+    if total <= 0.0 {
+        return None;
+    }
+    let mut target = rand::random::<f64>() * total;
+    for (bucket, weight) in weights {
+        if target < *weight {
+            return Some(*bucket);
+        }
+        target -= weight;
+    }
+    weights.last().map(|(bucket, _)| *bucket)
+}
+
+/// Pick the first track from a rank bucket which the user has not played, in order of
+/// `pick_key` starting from the given key.
+async fn sample_bucket(
+    db: &mut DbConn,
+    filter: &Filter,
+    user: i32,
+    bucket: i16,
+    from_key: f64,
+) -> Result<Option<deezer::Id>> {
+    let track = sqlx::query_scalar!(
+        r#"SELECT track.id AS "id!" FROM pickable_track($1, $2, $3, $4, $5) AS track
+        WHERE track.pick_bucket = $6 AND track.pick_key >= $7
+            AND NOT EXISTS (
+                SELECT 1 FROM game WHERE game.account_id = $8 AND game.track_id = track.id
+            )
+        ORDER BY track.pick_key
+        LIMIT 1"#,
+        &filter.genre_ids,
+        filter.released_from(),
+        filter.released_until(),
+        filter.allow_explicit,
+        filter.region,
+        bucket,
+        from_key,
+        user,
+    )
+    .fetch_optional(db)
+    .await
+    .wrap_err("error sampling a track from a rank bucket")?;
+    Ok(track.map(From::from))
+}
+
+/// Pick the first track the user has not played, in order of `pick_key` starting
+/// from the given key, regardless of rank.
+async fn sample_unplayed(
+    db: &mut DbConn,
+    filter: &Filter,
+    user: i32,
+    from_key: f64,
+) -> Result<Option<deezer::Id>> {
+    let track = sqlx::query_scalar!(
+        r#"SELECT track.id AS "id!" FROM pickable_track($1, $2, $3, $4, $5) AS track
+        WHERE track.pick_key >= $6
+            AND NOT EXISTS (
+                SELECT 1 FROM game WHERE game.account_id = $7 AND game.track_id = track.id
+            )
+        ORDER BY track.pick_key
+        LIMIT 1"#,
+        &filter.genre_ids,
+        filter.released_from(),
+        filter.released_until(),
+        filter.allow_explicit,
+        filter.region,
+        from_key,
+        user,
+    )
+    .fetch_optional(db)
+    .await
+    .wrap_err("error sampling an unplayed track")?;
+    Ok(track.map(From::from))
+}
+
+/// Pick the track the user last played longest ago, for when they have played every
+/// available track.
+async fn least_recently_played(
+    db: &mut DbConn,
+    filter: &Filter,
+    user: i32,
+) -> Result<Option<deezer::Id>> {
+    let track = sqlx::query_scalar!(
+        r#"SELECT track.id AS "id!" FROM game
+        INNER JOIN pickable_track($2, $3, $4, $5, $6) AS track ON game.track_id = track.id
+        WHERE game.account_id = $1
+        GROUP BY track.id
+        ORDER BY MAX(game.started_at) ASC
+        LIMIT 1"#,
+        user,
+        &filter.genre_ids,
+        filter.released_from(),
+        filter.released_until(),
+        filter.allow_explicit,
+        filter.region,
+    )
+    .fetch_optional(db)
+    .await
+    .wrap_err("error querying for the least recently played track")?;
+    Ok(track.map(From::from))
 }
 
 /// Get the current daily track, or pick a new one if none is set.
@@ -116,7 +513,7 @@ pub async fn daily(db: &mut DbConn) -> Result<deezer::Id> {
     let track = pick_daily(&mut *db).await?;
     sqlx::query!(
         "INSERT INTO daily_track (track_id) VALUES ($1)",
-        i32::from(track)
+        i64::from(track)
     )
     .execute(db)
     .await
@@ -126,62 +523,23 @@ pub async fn daily(db: &mut DbConn) -> Result<deezer::Id> {
 
 /// Pick a track for today, preferring more popular tracks.
 ///
-/// Avoids repeating recent tracks.
+/// Avoids repeating recent tracks. The daily track is the same for everyone, so it
+/// only excludes explicit tracks if the instance-wide default does.
 async fn pick_daily(db: &mut DbConn) -> Result<deezer::Id> {
     // We only do this once a day, so it's fine to always refresh first.
-    refresh_all(db).await?;
-    loop {
-        let track = sqlx::query_scalar!(
-            "SELECT track.id FROM track
-            LEFT JOIN daily_track ON track.id = daily_track.track_id
-            ORDER BY
-                daily_track.for_day ASC NULLS FIRST,
-                RANDOM() * track.deezer_rank DESC
-            LIMIT 1",
-        )
-        .fetch_one(&mut *db)
-        .await
-        .wrap_err("couldn't find any track for the daily")?
-        .into();
-        if let Some(track) = ensure_exists(db, track).await? {
-            return Ok(track);
-        }
-    }
-}
-
-/// Return the track if it still exists on Deezer, otherwise remove it from the
-/// cache and return None.
-async fn ensure_exists(db: &mut DbConn, track_id: deezer::Id) -> Result<Option<deezer::Id>> {
-    if deezer::track(track_id).await?.is_some() {
-        Ok(Some(track_id))
-    } else {
-        sqlx::query!("DELETE FROM track WHERE id = $1", i32::from(track_id))
-            .execute(db)
-            .await
-            .wrap_err("error deleting ghost track")?;
-        Ok(None)
-    }
-}
-
-/// Refresh the database with fresh data in the most popular genres from Deezer.
-async fn refresh_all(db: &mut DbConn) -> Result<()> {
-    let genres = deezer::genres().await?;
-    let mut inserter = BulkTrackInserter::new(db);
-    for genre in genres {
-        let chart = deezer::chart(genre.id).await?;
-        for track in chart {
-            inserter.insert_track(&track).await?;
-        }
-    }
-    Ok(())
-}
-
-/// Refresh the database with fresh data in the specified genre from Deezer.
-async fn refresh_genre(db: &mut DbConn, genre_id: deezer::Id) -> Result<()> {
-    let chart = deezer::chart(genre_id).await?;
-    let mut inserter = BulkTrackInserter::new(db);
-    for track in chart {
-        inserter.insert_track(&track).await?;
-    }
-    Ok(())
+    refresh::all(db).await?;
+    let track = sqlx::query_scalar!(
+        r#"SELECT track.id AS "id!" FROM pickable_track('{}', NULL, NULL, $1, $2) AS track
+        LEFT JOIN daily_track ON track.id = daily_track.track_id
+        ORDER BY
+            daily_track.for_day ASC NULLS FIRST,
+            RANDOM() * track.deezer_rank DESC
+        LIMIT 1"#,
+        User::allows_explicit_by_default(),
+        super::region(),
+    )
+    .fetch_one(db)
+    .await
+    .wrap_err("couldn't find any track for the daily")?;
+    Ok(track.into())
 }
@@ -0,0 +1,308 @@
+//! Keep the music data in the database up to date with the music provider.
+use std::time::Instant;
+
+use super::{
+    bulk_insert::{fetch_each, BulkInserter},
+    insert,
+    pick::Era,
+    Pool,
+};
+use crate::{deezer, provider, provider::Unavailable, DbConn};
+use eyre::{Context, Result};
+use sqlx::Connection;
+
+/// How long after a track was last verified before it should be checked again.
+const STALE_AFTER_DAYS: i32 = 7;
+
+/// The maximum number of related artists to include the top tracks of in an artist game.
+const MAX_RELATED_ARTISTS: usize = 5;
+
+/// The maximum number of stale tracks to check in one catalog refresh, so that a
+/// refresh doesn't use too much of the provider's rate limit.
+const VERIFY_BATCH_SIZE: i64 = 500;
+
+/// Refresh the whole catalog: fetch fresh charts for every genre (updating ranks),
+/// then re-check tracks which haven't been verified recently, removing any which
+/// the provider no longer serves, and finally recalculate the pick weights.
+pub async fn catalog(db: &mut DbConn) -> Result<()> {
+    all(db).await.wrap_err("error refreshing genre charts")?;
+    verify_stale(db)
+        .await
+        .wrap_err("error verifying stale tracks")?;
+    pick_weights(db)
+        .await
+        .wrap_err("error recalculating pick weights")
+}
+
+/// Refresh the database with fresh data in the most popular genres.
+///
+/// Charts and albums are fetched concurrently, and the time taken by each stage is logged.
+pub async fn all(db: &mut DbConn) -> Result<()> {
+    let started = Instant::now();
+    let genres = provider::get().genres().await?;
+    let genre_ids: Vec<deezer::Id> = genres.iter().map(|genre| genre.id).collect();
+    let charts = fetch_each(genre_ids, "genre chart", |genre_id| {
+        provider::get().chart(genre_id)
+    })
+    .await?;
+    let tracks: Vec<_> = charts.into_iter().flat_map(|(_, chart)| chart).collect();
+    eprintln!(
+        "catalog refresh: fetched {} charts ({} tracks) in {:.1?}",
+        genres.len(),
+        tracks.len(),
+        started.elapsed()
+    );
+    insert_tracks(db, &tracks, Pool::Chart).await?;
+    eprintln!("catalog refresh: finished in {:.1?}", started.elapsed());
+    Ok(())
+}
+
+/// Refresh the database with fresh data in the specified genre.
+pub async fn genre(db: &mut DbConn, genre_id: deezer::Id) -> Result<()> {
+    let chart = provider::get().chart(genre_id).await?;
+    insert_tracks(db, &chart, Pool::Chart).await
+}
+
+/// Refresh the database with fresh data for tracks released in the specified era.
+pub async fn era(db: &mut DbConn, era: Era) -> Result<()> {
+    let tracks = provider::get()
+        .era_chart(era.start_year, era.end_year)
+        .await?;
+    insert_tracks(db, &tracks, Pool::Chart).await
+}
+
+/// Refresh the database with the top tracks of an artist, and optionally of up to
+/// [`MAX_RELATED_ARTISTS`] related artists. Returns the IDs of the tracks, or `None`
+/// if the artist doesn't exist.
+///
+/// Artists are chosen by players, so their tracks are only added to the guess pool and
+/// are never picked for other games.
+pub async fn artist(
+    db: &mut DbConn,
+    artist_id: deezer::Id,
+    related: bool,
+) -> Result<Option<Vec<deezer::Id>>> {
+    let Some(artist) = provider::get().artist(artist_id).await? else {
+        return Ok(None);
+    };
+    let mut tracks = provider::get().artist_top(artist_id).await?;
+    if related {
+        let related = provider::get().related_artists(artist_id).await?;
+        let related_ids = related
+            .into_iter()
+            .take(MAX_RELATED_ARTISTS)
+            .map(|artist| artist.id)
+            .collect();
+        let tops = fetch_each(related_ids, "related artist", |artist_id| {
+            provider::get().artist_top(artist_id)
+        })
+        .await?;
+        tracks.extend(tops.into_iter().flat_map(|(_, top)| top));
+    }
+    insert::artists(db, &[artist]).await?;
+    insert_tracks(db, &tracks, Pool::Guess).await?;
+    Ok(Some(tracks.into_iter().map(|track| track.id).collect()))
+}
+
+/// Refresh the database with the tracks in a playlist, returning their IDs, or `None`
+/// if the playlist doesn't exist.
+///
+/// Playlists are chosen by players, so their tracks are only added to the guess pool
+/// and are never picked for other games.
+pub async fn playlist(db: &mut DbConn, playlist_id: deezer::Id) -> Result<Option<Vec<deezer::Id>>> {
+    let Some(playlist) = provider::get().playlist(playlist_id).await? else {
+        return Ok(None);
+    };
+    let tracks = provider::get().playlist_tracks(playlist_id).await?;
+    insert::playlist(db, &playlist).await?;
+    insert_tracks(db, &tracks, Pool::Guess).await?;
+    Ok(Some(tracks.into_iter().map(|track| track.id).collect()))
+}
+
+/// Insert tracks into the given pool, fetching their albums concurrently first.
+async fn insert_tracks(db: &mut DbConn, tracks: &[deezer::Track], pool: Pool) -> Result<()> {
+    let mut inserter = BulkInserter::new(db, pool);
+    let started = Instant::now();
+    let albums = inserter.prefetch_albums(tracks).await?;
+    eprintln!(
+        "catalog refresh: fetched {albums} new albums in {:.1?}",
+        started.elapsed()
+    );
+    let started = Instant::now();
+    let mut saved = 0;
+    for track in tracks {
+        match inserter.insert_track(track).await {
+            Ok(()) => saved += 1,
+            Err(e) if Unavailable::caused(&e) => return Err(e),
+            Err(e) => eprintln!("skipping track {} which failed to insert: {e:?}", track.id),
+        }
+    }
+    inserter.flush().await?;
+    eprintln!(
+        "catalog refresh: saved {saved} tracks in {:.1?}",
+        started.elapsed()
+    );
+    Ok(())
+}
+
+/// Re-fetch the least recently verified tracks which are stale, updating their data
+/// or removing them if they no longer exist.
+///
+/// This runs regularly in the background, so that tracks can be picked without
+/// checking that they still exist first.
+pub async fn verify_stale(db: &mut DbConn) -> Result<()> {
+    let stale: Vec<deezer::Id> = sqlx::query_scalar!(
+        "SELECT id FROM track
+        WHERE gone_at IS NULL AND verified_at < TIMEZONE('utc', NOW()) - MAKE_INTERVAL(days => $1)
+        ORDER BY verified_at ASC
+        LIMIT $2",
+        STALE_AFTER_DAYS,
+        VERIFY_BATCH_SIZE,
+    )
+    .fetch_all(&mut *db)
+    .await
+    .wrap_err("error querying for stale tracks")?
+    .into_iter()
+    .map(From::from)
+    .collect();
+    let started = Instant::now();
+    // Tracks which fail to fetch are left stale, to be retried next time.
+    let fetched = fetch_each(stale, "stale track", |track_id| {
+        provider::get().track_uncached(track_id)
+    })
+    .await?;
+    let verified = fetched.len();
+    let mut missing = Vec::new();
+    // Existing tracks keep their pool, since tracks are never moved to a less pickable pool.
+    let mut inserter = BulkInserter::new(db, Pool::Guess);
+    for (track_id, track) in fetched {
+        match track {
+            Some(track) => match inserter.insert_track(&track).await {
+                Err(e) if Unavailable::caused(&e) => return Err(e),
+                Err(e) => {
+                    eprintln!("skipping stale track {track_id} which failed to insert: {e:?}");
+                }
+                Ok(()) => {}
+            },
+            None => missing.push(track_id),
+        }
+    }
+    inserter.flush().await?;
+    for track_id in &missing {
+        remove(db, *track_id).await?;
+    }
+    eprintln!(
+        "verified {verified} stale tracks in {:.1?}, removed {}",
+        started.elapsed(),
+        missing.len()
+    );
+    Ok(())
+}
+
+/// Recalculate the total rank and number of the pickable tracks in each rank bucket,
+/// overall and per genre, which is used to pick tracks weighted by rank (see [`super::pick`]).
+///
+/// This recalculates every weight, so it runs as a background task rather than whenever
+/// tracks are added while creating a game.
+///
+/// Weights are calculated both with and without explicit tracks, for the configured region.
+pub async fn pick_weights(db: &mut DbConn) -> Result<()> {
+    let mut tx = db
+        .begin()
+        .await
+        .wrap_err("error starting transaction for pick weights")?;
+    sqlx::query!("DELETE FROM pick_weight")
+        .execute(&mut *tx)
+        .await
+        .wrap_err("error clearing pick weights")?;
+    for allow_explicit in [true, false] {
+        sqlx::query!(
+            "INSERT INTO pick_weight (genre_id, allow_explicit, bucket, weight, track_count)
+            SELECT 0, $1, track.pick_bucket, SUM(track.deezer_rank), COUNT(*)
+            FROM pickable_track('{}', NULL, NULL, $1, $2) AS track
+            GROUP BY track.pick_bucket",
+            allow_explicit,
+            super::region(),
+        )
+        .execute(&mut *tx)
+        .await
+        .wrap_err("error calculating pick weights")?;
+        sqlx::query!(
+            "INSERT INTO pick_weight (genre_id, allow_explicit, bucket, weight, track_count)
+            SELECT album_genre.genre_id, $1, track.pick_bucket, SUM(track.deezer_rank), COUNT(*)
+            FROM pickable_track('{}', NULL, NULL, $1, $2) AS track
+            INNER JOIN album_genre ON track.album_id = album_genre.album_id
+            GROUP BY album_genre.genre_id, track.pick_bucket",
+            allow_explicit,
+            super::region(),
+        )
+        .execute(&mut *tx)
+        .await
+        .wrap_err("error calculating per-genre pick weights")?;
+    }
+    tx.commit().await.wrap_err("error committing pick weights")
+}
+
+/// Remove a track which the provider no longer serves.
+///
+/// Tracks referenced by games can't be deleted, so are marked as gone instead, which
+/// stops them being picked.
+async fn remove(db: &mut DbConn, track_id: deezer::Id) -> Result<()> {
+    sqlx::query!(
+        "DELETE FROM track WHERE id = $1
+        AND NOT EXISTS (SELECT 1 FROM game WHERE track_id = $1)
+        AND NOT EXISTS (SELECT 1 FROM game_guess WHERE track_id = $1)
+        AND NOT EXISTS (SELECT 1 FROM daily_track WHERE track_id = $1)",
+        i64::from(track_id),
+    )
+    .execute(&mut *db)
+    .await
+    .wrap_err("error deleting ghost track")?;
+    sqlx::query!(
+        "UPDATE track SET gone_at = TIMEZONE('utc', NOW()) WHERE id = $1",
+        i64::from(track_id),
+    )
+    .execute(db)
+    .await
+    .wrap_err("error marking ghost track as gone")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::PgPool;
+
+    /// Replay a catalog refresh from the recorded Deezer fixtures into a fresh database.
+    #[sqlx::test]
+    #[ignore = "needs a Postgres server at DATABASE_URL"]
+    async fn all_replays_into_database(pool: PgPool) -> Result<()> {
+        let deezer = Box::leak(Box::new(deezer::Deezer::replay()));
+        let mut db = pool.acquire().await?;
+        provider::scope(deezer, all(&mut db)).await?;
+        let tracks: Vec<(i64, String)> =
+            sqlx::query_as("SELECT id, pool::text FROM track ORDER BY id")
+                .fetch_all(&mut *db)
+                .await?;
+        assert_eq!(
+            tracks,
+            [3_135_553, 3_135_556, 67_238_732, 67_238_735].map(|id| (id, "chart".into())),
+            "every charting track should be in the chart pool"
+        );
+        let album_genres: Vec<(i64, i64)> = sqlx::query_as(
+            "SELECT album_id, genre_id FROM album_genre ORDER BY album_id, genre_id",
+        )
+        .fetch_all(&mut *db)
+        .await?;
+        assert_eq!(
+            album_genres,
+            [
+                (302_127, 106),
+                (302_127, 113),
+                (6_575_789, 113),
+                (6_575_789, 132)
+            ]
+        );
+        Ok(())
+    }
+}
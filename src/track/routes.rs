@@ -1,6 +1,11 @@
 //! API routes for track resources.
 use super::Meta;
-use crate::{deezer, ApiError};
+use crate::{
+    deezer, provider,
+    throttle::{self, Throttle},
+    ApiError,
+};
+use eyre::Result;
 use rocket::{get, routes, serde::json::Json};
 use serde::Serialize;
 
@@ -18,14 +23,23 @@ struct SearchResults {
 
 /// Search for a track by name.
 #[get("/tracks?<q>")]
-async fn search_track(q: &str) -> Result<Json<SearchResults>, ApiError> {
+async fn search_track(
+    _throttle: Throttle<throttle::Search>,
+    q: &str,
+) -> Result<Json<SearchResults>, ApiError> {
+    Ok(Json(search(q).await?))
+}
+
+/// Find the five most popular available tracks matching a search query.
+async fn search(q: &str) -> Result<SearchResults> {
     if q.is_empty() {
-        return Ok(Json(SearchResults::default()));
+        return Ok(SearchResults::default());
     }
-    let mut tracks = deezer::track_search(q).await?;
+    let mut tracks = provider::get().track_search(q).await?;
+    tracks.retain(deezer::Track::is_available);
     tracks.sort_by_key(|track| std::cmp::Reverse(track.rank));
     let meta = tracks.into_iter().take(5).map(From::from).collect();
-    Ok(Json(SearchResults { tracks: meta }))
+    Ok(SearchResults { tracks: meta })
 }
 
 /// JSON response to a genres list query.
@@ -38,6 +52,38 @@ struct Genres {
 /// Get a list of common genres.
 #[get("/genres")]
 async fn list_genres() -> Result<Json<Genres>, ApiError> {
-    let genres = deezer::genres().await?;
+    let genres = provider::get().genres().await?;
     Ok(Json(Genres { genres }))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::provider::fake::UNREADABLE_TRACK_ID;
+
+    /// Use the fake provider, with no region configured.
+    fn set_up() {
+        provider::use_fake();
+        super::super::REGION.get_or_init(|| None);
+    }
+
+    #[rocket::async_test]
+    async fn search_returns_best_available_tracks() {
+        set_up();
+        let results = search("song").await.expect("search should succeed");
+        let ids: Vec<_> = results.tracks.iter().map(|track| track.id).collect();
+        assert!(!ids.contains(&UNREADABLE_TRACK_ID));
+        assert_eq!(
+            ids,
+            [8, 6, 5, 4, 3].map(deezer::Id),
+            "should be the five highest ranked available tracks, best first"
+        );
+    }
+
+    #[rocket::async_test]
+    async fn empty_search_finds_nothing() {
+        set_up();
+        let results = search("").await.expect("search should succeed");
+        assert!(results.tracks.is_empty());
+    }
+}
@@ -4,6 +4,19 @@ use crate::DbConn;
 use chrono::{DateTime, Utc};
 use eyre::{Context, Report, Result};
 use serde::Serialize;
+use std::sync::OnceLock;
+
+/// Whether explicit tracks may be picked for users who haven't chosen, set on startup.
+static ALLOW_EXPLICIT: OnceLock<bool> = OnceLock::new();
+
+/// Initialise user account defaults using the given config.
+///
+/// Must only be called once.
+pub fn init(config: &crate::Config) {
+    ALLOW_EXPLICIT
+        .set(config.allow_explicit)
+        .expect("user::database::init must only be called once");
+}
 
 /// The database model of a user account.
 #[derive(Serialize)]
@@ -15,6 +28,9 @@ pub struct User {
     pub display_name: Option<String>,
     /// The time this account was created.
     pub created_at: DateTime<Utc>,
+    /// Whether tracks with explicit lyrics may be picked for this user's games, or
+    /// `None` to use the instance-wide default.
+    pub allow_explicit: Option<bool>,
     /// The hash of the user's login secret (the login secret forms a part of the login token).
     #[serde(skip)]
     pub secret_hash: Vec<u8>,
@@ -65,6 +81,38 @@ impl User {
         .map_err(Report::from)
     }
 
+    /// Set whether tracks with explicit lyrics may be picked for this user's games.
+    ///
+    /// Pass `None` to use the instance-wide default.
+    pub async fn set_allow_explicit(
+        self,
+        db: &mut DbConn,
+        allow_explicit: Option<bool>,
+    ) -> Result<Self> {
+        sqlx::query_as!(
+            User,
+            "UPDATE account SET allow_explicit = $1 WHERE id = $2 RETURNING *",
+            allow_explicit,
+            self.id
+        )
+        .fetch_one(db)
+        .await
+        .map_err(Report::from)
+    }
+
+    /// Whether tracks with explicit lyrics may be picked for users who haven't chosen.
+    pub fn allows_explicit_by_default() -> bool {
+        *ALLOW_EXPLICIT
+            .get()
+            .expect("user defaults used before initialisation")
+    }
+
+    /// Whether tracks with explicit lyrics may be picked for this user's games.
+    pub fn allows_explicit(&self) -> bool {
+        self.allow_explicit
+            .unwrap_or_else(Self::allows_explicit_by_default)
+    }
+
     /// Delete this user account.
     pub async fn delete(self, db: &mut DbConn) -> Result<()> {
         sqlx::query!("DELETE FROM account WHERE id = $1", self.id)
@@ -6,4 +6,12 @@ mod session;
 
 pub use database::User;
 pub use routes::routes;
-pub use session::{init, Session};
+pub use session::Session;
+
+/// Initialise the user account and session systems using the given config.
+///
+/// Must only be called once.
+pub fn init(config: &crate::Config) {
+    database::init(config);
+    session::init(config);
+}
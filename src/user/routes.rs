@@ -1,5 +1,8 @@
 //! API routes and request guards for user accounts and sessions.
-use crate::{track, ApiError, Connection, DbConn, Session, Transaction, User};
+use crate::{
+    throttle::{self, Throttle},
+    ApiError, Connection, DbConn, Session, Transaction, User,
+};
 use rocket::{
     delete, get, patch, post,
     request::{self, FromRequest},
@@ -8,7 +11,7 @@ use rocket::{
     Request,
 };
 
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 
 /// Collect API routes for user accounts and sessions.
 pub fn routes() -> Vec<rocket::Route> {
@@ -39,12 +42,6 @@ impl Session {
     }
 }
 
-/// JSON response to a track search query.
-#[derive(Default, Serialize)]
-struct SearchResults {
-    /// The tracks that were found.
-    tracks: Vec<track::Meta>,
-}
 /// The response body for a newly created user.
 #[derive(Serialize)]
 struct NewUser {
@@ -54,7 +51,10 @@ struct NewUser {
 
 /// Create a new user.
 #[post("/users/me")]
-async fn new_user(mut conn: Connection) -> Result<Json<NewUser>, ApiError> {
+async fn new_user(
+    _throttle: Throttle<throttle::NewUser>,
+    mut conn: Connection,
+) -> Result<Json<NewUser>, ApiError> {
     let (_user, secret) = User::create(&mut conn).await?;
     Ok(Json(NewUser { login: secret }))
 }
@@ -70,6 +70,22 @@ async fn get_user(mut conn: Connection, auth: Session) -> Result<Json<User>, Api
 struct UpdateUser {
     /// The new display name for the user, or `null` to keep the current one.
     display_name: Option<String>,
+    /// Whether tracks with explicit lyrics may be picked for the user's games, `null` to
+    /// use the instance-wide default, or left out to keep the current setting.
+    #[serde(default, deserialize_with = "explicit_null")]
+    #[allow(clippy::option_option)]
+    allow_explicit: Option<Option<bool>>,
+}
+
+/// Deserialize a field which may be left out, so that an explicit `null` is
+/// `Some(None)` rather than `None`.
+#[allow(clippy::option_option)]
+fn explicit_null<'de, T, D>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    Option::deserialize(deserializer).map(Some)
 }
 
 /// Update the authenticated user's account.
@@ -83,6 +99,9 @@ async fn update_user(
     if let Some(display_name) = &body.display_name {
         user = user.set_display_name(&mut tx, Some(display_name)).await?;
     }
+    if let Some(allow_explicit) = body.allow_explicit {
+        user = user.set_allow_explicit(&mut tx, allow_explicit).await?;
+    }
     tx.commit().await?;
     Ok(Json(user))
 }
@@ -117,6 +136,7 @@ struct SessionResponse {
 /// Create a new session using a secret login token.
 #[post("/sessions", data = "<body>")]
 async fn login_secret(
+    _throttle: Throttle<throttle::Login>,
     mut conn: Connection,
     body: Json<Login>,
 ) -> Result<Json<SessionResponse>, ApiError> {
@@ -129,3 +149,25 @@ async fn login_secret(
     let session = user.session_token();
     Ok(Json(SessionResponse { session }))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::serde::json::serde_json;
+
+    #[test]
+    fn allow_explicit_can_be_reset() {
+        let parse = |json| {
+            serde_json::from_str::<UpdateUser>(json)
+                .expect("body should parse")
+                .allow_explicit
+        };
+        assert_eq!(parse("{}"), None, "a missing field keeps the setting");
+        assert_eq!(
+            parse(r#"{"allow_explicit": null}"#),
+            Some(None),
+            "null resets the setting to the default"
+        );
+        assert_eq!(parse(r#"{"allow_explicit": false}"#), Some(Some(false)));
+    }
+}
@@ -82,6 +82,11 @@ pub struct Session {
 }
 
 impl Session {
+    /// Get the ID of the account this session is for.
+    pub const fn account_id(&self) -> i32 {
+        self.account_id
+    }
+
     /// Validate and decode a session from an `Authorization` header.
     pub fn from_auth_header(header: &str) -> Result<Self, &'static str> {
         let conf = SESSION_CONFIG
@@ -98,4 +103,27 @@ impl Session {
         }
         Ok(claim)
     }
+
+    /// Create a session token for an account, for tests.
+    ///
+    /// This sets up the session system with a fixed key if it hasn't been already.
+    #[cfg(test)]
+    pub fn test_token(account_id: i32) -> String {
+        let conf = SESSION_CONFIG.get_or_init(|| SessionConfig {
+            key: Hmac::new_from_slice(b"test key").expect("any length is valid"),
+            session_lifetime: chrono::Duration::hours(1),
+        });
+        let header = jwt::Header {
+            algorithm: jwt::AlgorithmType::Hs512,
+            ..Default::default()
+        };
+        let claim = Self {
+            account_id,
+            created_at: Utc::now(),
+        };
+        let token = jwt::Token::new(header, claim)
+            .sign_with_key(&conf.key)
+            .expect("signing to succeed");
+        token.as_str().to_string()
+    }
 }
@@ -44,8 +44,8 @@ fn filesystem_index() -> (ContentType, String) {
     let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
         .join("dist")
         .join("index.html");
-    let content =
-        std::fs::read_to_string(&path).unwrap_or_else(|_| panic!("failed to read {path:?}"));
+    let content = std::fs::read_to_string(&path)
+        .unwrap_or_else(|_| panic!("failed to read {}", path.display()));
     (ContentType::HTML, content)
 }
 
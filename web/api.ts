@@ -208,16 +208,21 @@ function useMutate<Options, Result>(
 
 type UpdateUser = {
     displayName?: string | null;
+    allowExplicit?: boolean | null;
 };
 
-/** Update the current user's display name.
+/** Update the current user's display name or explicit content preference.
  *
  * @param displayName The new display name, or null to not change it.
+ * @param allowExplicit Whether explicit songs may be picked, or null to not change it.
  * @returns The updated user account.
  */
-async function updateUser({ displayName = null }: UpdateUser): Promise<User> {
+async function updateUser({
+    displayName = null,
+    allowExplicit = null,
+}: UpdateUser): Promise<User> {
     const response = await endpoint("PATCH", "/users/me", {
-        body: { display_name: displayName },
+        body: { display_name: displayName, allow_explicit: allowExplicit },
     });
     return await response.json();
 }
@@ -238,28 +243,59 @@ export function useDeleteUser() {
 }
 
 type NewGame = {
-    genreId?: number | null;
+    genreIds?: number[];
     daily?: boolean;
     timed?: boolean;
+    difficulty?: Difficulty | null;
+    era?: { start_year: number; end_year: number } | null;
+    decade?: number | null;
+    artistId?: number | null;
+    relatedArtists?: boolean;
+    playlistId?: number | null;
 };
 
 /** Create a new game (requires login).
  *
- * @param genreId The genre to pick a song from, or null to pick randomly.
+ * @param genreIds The genres to pick a song from, or empty to pick randomly.
  * @param daily Whether to play the daily game.
  * @param timed Whether to play a timed game mode.
+ * @param difficulty How strongly to prefer popular songs, or null for the default.
+ * @param era The range of years (inclusive) to pick a song from, or null for any. Must be
+ *     between 1900 and the current year, and cover at most 50 years.
+ * @param decade A shorthand for an era covering the decade starting with this year.
+ * @param artistId The artist to pick one of the top songs of, or null for any.
+ * @param relatedArtists Whether to also pick from artists related to artistId.
+ * @param playlistId The playlist to pick a song from, or null to not use a playlist.
  * @returns The new game.
  *
- * If daily is set, genreId and timed must not be. Will also error if the user
+ * If daily is set, genreIds, timed, difficulty, era, decade, artistId and playlistId
+ * must not be. If artistId is set, genreIds, era and decade must not be. If playlistId
+ * is set, genreIds, era, decade and artistId must not be. Will also error if the user
  * has already played the daily game today, or if they already have a game active.
  */
 async function newGame({
-    genreId = null,
+    genreIds = [],
     daily = false,
     timed = false,
+    difficulty = null,
+    era = null,
+    decade = null,
+    artistId = null,
+    relatedArtists = false,
+    playlistId = null,
 }: NewGame = {}): Promise<Game> {
     const response = await endpoint("POST", "/games", {
-        body: { genre_id: genreId, daily, timed },
+        body: {
+            genre_ids: genreIds,
+            daily,
+            timed,
+            difficulty,
+            era,
+            decade,
+            artist_id: artistId,
+            related_artists: relatedArtists,
+            playlist_id: playlistId,
+        },
     });
     return await response.json();
 }
@@ -312,6 +348,7 @@ export type User = {
     id: number;
     displayName: string;
     createdAt: string;
+    allowExplicit: boolean | null;
 };
 
 /** The recent game IDs for the current user. */
@@ -326,7 +363,12 @@ export type Game = {
     startedAt: string;
     isDaily: boolean;
     isTimed: boolean;
-    genre: Genre | null;
+    genres: Genre[];
+    difficulty: Difficulty;
+    era: Era | null;
+    artist: Artist | null;
+    relatedArtists: boolean;
+    playlist: Playlist | null;
     guesses: Guess[];
     timedGuess: GuessTiming | null;
     won: boolean | null;
@@ -334,6 +376,31 @@ export type Game = {
     constants: GameConstants;
 };
 
+/** An artist, as returned by the API. */
+export type Artist = {
+    id: number;
+    name: string;
+    link: string;
+    picture: string;
+};
+
+/** A playlist, as returned by the API. */
+export type Playlist = {
+    id: number;
+    title: string;
+    link: string;
+    picture: string;
+};
+
+/** A range of years (inclusive) a game's track was released in. */
+export type Era = {
+    startYear: number;
+    endYear: number;
+};
+
+/** How strongly a game prefers popular tracks. */
+export type Difficulty = "mainstream" | "balanced" | "deepCuts";
+
 /** A genre, as returned by the API. */
 export type Genre = {
     id: number;
@@ -362,6 +429,15 @@ export type Track = {
     artistName: string;
     albumTitle: string;
     albumCover: string;
+    /** Length in seconds. */
+    duration: number | null;
+    explicitLyrics: boolean | null;
+    bpm: number | null;
+    isrc: string | null;
+    /** The album's release date, as `YYYY-MM-DD`. */
+    releaseDate: string | null;
+    /** The kind of album release, such as "album", "single", "ep" or "compile". */
+    recordType: string | null;
 };
 
 /** Game constants, as returned by the API. */
@@ -10,11 +10,11 @@ import {
 export type GameTypeAttrs = {
     isDaily?: boolean;
     isTimed?: boolean;
-    genre?: Genre | null;
+    genres?: Genre[];
 };
 
 export function GameType({
-    game: { isDaily = false, isTimed = false, genre = null },
+    game: { isDaily = false, isTimed = false, genres = [] },
     className = "",
 }: {
     game: GameTypeAttrs;
@@ -37,12 +37,12 @@ export function GameType({
                 <FontAwesomeIcon icon={icon} className="game_type__icon" />
                 {name}
             </span>
-            {genre && (
-                    <span className="game_type">
+            {genres.map(genre => (
+                    <span className="game_type" key={genre.id}>
                     <FontAwesomeIcon icon={faMusic} className="game_type__icon" />
                     {genre.name}
                     </span>
-            )}
+            ))}
         </span>
     );
 }
@@ -29,7 +29,7 @@ export function NewGame() {
         <>
             <h1 className="title">New Game</h1>
             <h2 className="sub">
-                <GameType game={{ isDaily: false, isTimed: timed, genre }} />
+                <GameType game={{ isDaily: false, isTimed: timed, genres: genre ? [genre] : [] }} />
             </h2>
             <label htmlFor="genre_search" className="sub">
                 Pick a genre or leave to select randomly
@@ -70,7 +70,7 @@ export function StartGame({ timed, genre }: { timed: boolean; genre: Genre | nul
         return <button className="submit">...</button>;
     }
     const startGame = async () => {
-        const game = await mutate({ timed, genreId: genre?.id });
+        const game = await mutate({ timed, genreIds: genre ? [genre.id] : [] });
         navigate(`/games/${game!.id}`);
     };
     return (